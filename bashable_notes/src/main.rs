@@ -25,16 +25,17 @@ fn handler(req: &mut Request) -> IronResult<Response> {
         string_path = String::from("index.html");
     }
 
-    let mut content = String::new();
+    let mut content = Vec::new();
     let mut path = Path::new(&string_path);
     let data = match STATIC.find(&string_path) {
         Some(file) => file.contents,
         None => {
             path = Path::new(&string_path);
             if path.exists() {
+                // read as bytes so images and other binary files can be served
                 let mut f = File::open(path).unwrap();
-                f.read_to_string(&mut content).unwrap();
-                content.as_bytes()
+                f.read_to_end(&mut content).unwrap();
+                content.as_slice()
             } else {
                 return Ok(Response::with(status::NotFound));
             }
@@ -4,12 +4,72 @@ import MediaQuery from 'react-responsive';
 import hljs from 'highlight.js';
 
 import { FileTree } from './filetree';
+import { Terminal } from './terminal';
 import { Container, Spinner } from './uikit';
 import "./style.css";
 import "highlight.js/styles/default.css";
 
 export const FileTreeWidth = 350;
 
+const output_pane = (title, pane_id, output, subtext = "command") => `
+	<li class="uk-open">
+		<a class="uk-accordion-title uk-text-small" href="#"><span class="uk-text-bold">${title}</span> <span class="uk-text-muted">${subtext}</span></a>
+		<div class="uk-accordion-content">
+			<pre><code id="${pane_id}" class="language-nohighlight hljs">${output}</code></pre>
+		</div>
+	</li>`;
+
+const escape_html = (text) => {
+	let div = document.createElement("div");
+	div.textContent = text;
+	return div.innerHTML;
+};
+
+const format_size = (bytes) => {
+	let units = ["B", "KB", "MB", "GB"];
+	let unit = 0;
+	while (bytes >= 1024 && unit < units.length - 1) {
+		bytes /= 1024;
+		unit++;
+	}
+	return `${unit == 0 ? bytes : bytes.toFixed(1)} ${units[unit]}`;
+};
+
+const artifacts_pane = (block_id, artifacts) => `
+	<li class="uk-open">
+		<a class="uk-accordion-title uk-text-small" href="#"><span class="uk-text-bold">Artifacts</span> <span class="uk-text-muted">${artifacts.length} files</span></a>
+		<div class="uk-accordion-content" id="${block_id}-artifacts">
+			${artifacts.map((artifact) => {
+				let url = `${encodeURI(artifact.path)}?t=${new Date().getTime()}`;
+				let preview = artifact.mime.startsWith("image/") ? `<img src="${url}" alt="${escape_html(artifact.path)}"><br>` : "";
+				return `<div class="artifact">${preview}<a href="${url}" download>${escape_html(artifact.path)}</a> <span class="uk-text-muted">${artifact.mime}, ${format_size(artifact.size)}</span></div>`;
+			}).join("")}
+		</div>
+	</li>`;
+
+// output that isn't text is sent base64 encoded, shown inline if the browser can
+const binary_pane = (block_id, binary) => {
+	let url = `data:${binary.mime};base64,${binary.data}`;
+	let preview = "";
+	if (binary.mime.startsWith("image/")) preview = `<img src="${url}"><br>`;
+	if (binary.mime == "application/pdf") preview = `<embed src="${url}" type="application/pdf" width="100%" height="600"><br>`;
+	return `
+	<li class="uk-open">
+		<a class="uk-accordion-title uk-text-small" href="#"><span class="uk-text-bold">Output</span> <span class="uk-text-muted">${binary.mime}, ${format_size(binary.size)}</span></a>
+		<div class="uk-accordion-content" id="${block_id}-binary">
+			${preview}<a href="${url}" download="${block_id}-output">Download</a>
+		</div>
+	</li>`;
+};
+
+// shown while an interactive block waits for input, removed with the streamed output
+const input_pane = (block_id) => `
+	<li class="uk-open block-input" id="${block_id}-input">
+		<form class="uk-form" data-block="${block_id}">
+			<input class="uk-input uk-form-small" type="text" placeholder="Input" autocomplete="off">
+		</form>
+	</li>`;
+
 class Document extends React.Component {
 	constructor(props) {
 		super(props);
@@ -26,27 +86,53 @@ class Document extends React.Component {
 					path: json_msg.Markdown.path,
                     markdown: json_msg.Markdown.markdown
 				});
-            } else if ("Output" in json_msg) {
+            } else if ("OutputChunk" in json_msg) {
+				let chunk = json_msg.OutputChunk;
+				let pane_id = `${chunk.id}-${chunk.stream.toLowerCase()}`;
+				let pane = document.getElementById(pane_id);
+				if (pane == null) {
+					let title = chunk.stream == "Stdout" ? "Output" : "Error";
+					document.getElementById(chunk.id).insertAdjacentHTML("beforeend", output_pane(title, pane_id, ""));
+					pane = document.getElementById(pane_id);
+				}
+
+				// the unfinished line is replaced by each chunk
+				let partial = pane.querySelector(".ansi-partial");
+				if (partial != null) partial.remove();
+				pane.insertAdjacentHTML("beforeend", chunk.data);
+				pane.insertAdjacentHTML("beforeend", `<span class="ansi-partial">${chunk.partial}</span>`);
+			} else if ("Output" in json_msg) {
 				console.log("New Output message");
-					
-				let insert_output = (title, output) => {
-					// remove old input nodes
-					let nodes = document.querySelectorAll(`#${json_msg.Output.id} > li`);
-					if (nodes.length > 0) {
-						document.getElementById(json_msg.Output.id).innerHTML = nodes[0].outerHTML;
-					}
+				let result = json_msg.Output;
+				let block = document.getElementById(result.id);
+
+				let summary = result.cached ? "command, cached" : `command, ${result.duration.toFixed(2)}s`;
+				if (result.signal != null) summary += `, killed by signal ${result.signal}`;
+				else if (result.exit_code != null && result.exit_code != 0) summary += `, exit code ${result.exit_code}`;
 
-					document.getElementById(json_msg.Output.id).innerHTML += `
-					<li class="uk-open">
-						<a class="uk-accordion-title uk-text-small" href="#"><span class="uk-text-bold">${title}</span> <span class="uk-text-muted">command</span></a>
-						<div class="uk-accordion-content">
-							<pre><code class="language-nohighlight hljs">${output}</code></pre>
-						</div>
-					</li>`;
+				let insert_output = (title, stream, output) => {
+					block.innerHTML += output_pane(title, `${result.id}-${stream}`, output, summary);
 				}
 				
-				if (json_msg.Output.stdout != "") insert_output("Output", json_msg.Output.stdout);
-				if (json_msg.Output.stderr != "") insert_output("Error", json_msg.Output.stderr);
+				// remove streamed output, keeping the input node
+				let nodes = document.querySelectorAll(`#${json_msg.Output.id} > li`);
+				if (nodes.length > 0) {
+					document.getElementById(json_msg.Output.id).innerHTML = nodes[0].outerHTML;
+				}
+
+				if (json_msg.Output.stdout != "") insert_output("Output", "stdout", json_msg.Output.stdout);
+				if (result.binary != null) block.innerHTML += binary_pane(result.id, result.binary);
+				if (json_msg.Output.stderr != "") insert_output("Error", "stderr", json_msg.Output.stderr);
+				if (json_msg.Output.status == "Cancelled") insert_output("Cancelled", "status", "");
+				if (json_msg.Output.status == "TimedOut") insert_output("Timed out", "status", "");
+				if (json_msg.Output.status == "OutOfMemory") {
+					insert_output("Error", "status", "Killed: the container hit its memory limit (see the notebook memory option)");
+				}
+				if (result.status == "Completed" && !result.success) insert_output("Failed", "status", "");
+				if (result.artifacts.length > 0) block.innerHTML += artifacts_pane(result.id, result.artifacts);
+
+				block.classList.toggle("block-failed", !result.success);
+				block.classList.toggle("block-succeeded", result.success);
 			
 				// refresh images
 				let images = document.querySelectorAll("img");
@@ -56,6 +142,36 @@ class Document extends React.Component {
 					images[i].src = src;
 				}
 				
+			} else if ("AwaitingInput" in json_msg) {
+				let id = json_msg.AwaitingInput.id;
+				let block = document.getElementById(id);
+				if (block == null) return;
+
+				let pane = document.getElementById(`${id}-input`);
+				if (pane == null) {
+					block.insertAdjacentHTML("beforeend", input_pane(id));
+					pane = document.getElementById(`${id}-input`);
+				}
+				pane.querySelector("input").focus();
+			} else if ("BuildLog" in json_msg) {
+				let log = json_msg.BuildLog;
+				if (log.path != this.state.path) return;
+
+				let pane = document.getElementById("build-log");
+				if (pane == null) {
+					document.querySelector(".notebook").insertAdjacentHTML("afterbegin",
+						`<ul uk-accordion="multiple: true">${output_pane("Build log", "build-log", "")}</ul>`);
+					pane = document.getElementById("build-log");
+				}
+				pane.appendChild(document.createTextNode(log.data));
+			} else if ("Error" in json_msg) {
+				let error = json_msg.Error;
+				let block = error.id == null ? null : document.getElementById(error.id);
+				if (block != null) {
+					block.insertAdjacentHTML("beforeend", output_pane("Error", `${error.id}-error`, escape_html(error.error)));
+				} else {
+					window.UIkit.notification({message: escape_html(error.error), status: "danger"});
+				}
 			} else if ("FileUpdate" in json_msg) {
 				console.log("New FileUpdate message");	
 				try {
@@ -74,6 +190,36 @@ class Document extends React.Component {
         } catch (e) {}
 	}
 	
+	on_click(e) {
+		let cancel = e.target.closest(".block-cancel");
+		if (cancel == null) return;
+
+		e.preventDefault();
+		let req = {
+			"Cancel": {
+				"id": cancel.dataset.block
+			}
+		};
+		this.props.socket.send(JSON.stringify(req));
+	}
+
+	// input typed into an interactive block, the server echoes it to the output
+	on_submit(e) {
+		let form = e.target.closest(".block-input form");
+		if (form == null) return;
+
+		e.preventDefault();
+		let input = form.querySelector("input");
+		let req = {
+			"Stdin": {
+				"id": form.dataset.block,
+				"data": input.value + "\n"
+			}
+		};
+		this.props.socket.send(JSON.stringify(req));
+		input.value = "";
+	}
+
 	componentDidUpdate() {
 		// re-highlight
 		hljs.initHighlighting.called = false;
@@ -85,15 +231,17 @@ class Document extends React.Component {
 
 	render() {
 		return (
-			<div>
+			<div onClick={(e) => this.on_click(e)} onSubmit={(e) => this.on_submit(e)}>
 				<MediaQuery minWidth={991}>
 					<div 
+						className="notebook"
 						style={{marginLeft: FileTreeWidth}} 
 						dangerouslySetInnerHTML={{__html: this.state.markdown}}>
 					</div>
 				</MediaQuery>
 				<MediaQuery maxWidth={991}>
 					<div 
+						className="notebook"
 						dangerouslySetInnerHTML={{__html: this.state.markdown}}>
 					</div>
 				</MediaQuery>
@@ -102,10 +250,21 @@ class Document extends React.Component {
 	} 
 }
 
-const NavBar = ({sideNavId}) => (
+const NavBar = ({sideNavId, paused, onPause, onResume, onRebuild, onRestart, onTerminal}) => (
 	<div className="uk-navbar-container uk-navbar-sticky" uk-navbar={""} uk-sticky={""}>
 		<div className="uk-navbar-left">
 			<a className="uk-navbar-item uk-logo">BashableNotes</a>
+			<ul className="uk-navbar-nav">
+				{paused
+					? <li><a href="#" onClick={onResume}><span uk-icon="icon: play" className="uk-margin-small-right"></span>Resume</a></li>
+					: <li><a href="#" onClick={onPause}><span uk-icon="icon: ban" className="uk-margin-small-right"></span>Pause</a></li>}
+				{onRestart &&
+					<li><a href="#" onClick={onRestart}><span uk-icon="icon: bolt" className="uk-margin-small-right"></span>Restart</a></li>}
+				{onRebuild &&
+					<li><a href="#" onClick={onRebuild}><span uk-icon="icon: refresh" className="uk-margin-small-right"></span>Rebuild</a></li>}
+				{onTerminal &&
+					<li><a href="#" onClick={onTerminal}><span uk-icon="icon: code" className="uk-margin-small-right"></span>Terminal</a></li>}
+			</ul>
 		</div>
 		<MediaQuery maxWidth={991}>
 			<div className="uk-navbar-right">
@@ -119,24 +278,59 @@ class App extends React.Component {
 	constructor(props) {
 		super(props);
 		this.state = {
-			socket: new WebSocket("ws://127.0.0.1:3012")
+			socket: new WebSocket("ws://127.0.0.1:3012"),
+			paused: false,
+			path: null,
+			terminal: false
 		};
 		
 		this.state.socket.addEventListener("open", () => {
 			this.state.socket.send("\"GetTree\"");
 		});
+		this.state.socket.addEventListener("message", (msg) => this.new_message(msg));
+	}
+
+	new_message(msg) {
+		try {
+			let json_msg = JSON.parse(msg.data);
+			if (json_msg == "Paused") {
+				this.setState({paused: true});
+			} else if (json_msg == "Resumed") {
+				this.setState({paused: false});
+			} else if ("Markdown" in json_msg) {
+				this.setState({path: json_msg.Markdown.path});
+			}
+		} catch (e) {}
+	}
+
+	send(req) {
+		this.state.socket.send(JSON.stringify(req));
+	}
+
+	restart() {
+		this.send({"RestartEnvironment": {"path": this.state.path, "environment": null}});
+		window.UIkit.notification({message: "Restarted the notebook's containers", status: "primary"});
 	}
 
 	render() {
 		return (
 			<div>
-				<NavBar sideNavId="file-tree-nav" />
+				<NavBar 
+					sideNavId="file-tree-nav" 
+					paused={this.state.paused}
+					onPause={() => this.send("Pause")}
+					onResume={() => this.send("Resume")}
+					onRebuild={this.state.path && (() => this.send({"Rebuild": {"path": this.state.path}}))}
+					onRestart={this.state.path && (() => this.restart())}
+					onTerminal={this.state.path && (() => this.setState({terminal: !this.state.terminal}))} />
 				<div style={{paddingTop: 20}}>
 					<Container>
 						<FileTree width={FileTreeWidth-40} sideNavId="file-tree-nav" socket={this.state.socket}/>
 						<Document socket={this.state.socket}/>
 					</Container>
 				</div>
+				{this.state.terminal &&
+					<Terminal key={this.state.path} path={this.state.path} socket={this.state.socket}/>}
 			</div>
 		) 
 	}
@@ -0,0 +1,76 @@
+import React from "react";
+import { Terminal as XTerm } from "xterm";
+import * as fit from "xterm/lib/addons/fit/fit";
+import "xterm/dist/xterm.css";
+
+XTerm.applyAddon(fit);
+
+let next_id = 0;
+
+// A shell in the container the notebook runs in, attached over the websocket
+export class Terminal extends React.Component {
+	constructor(props) {
+		super(props);
+		this.id = `terminal-${next_id++}`;
+		this.closed = false;
+		this.on_message = (msg) => this.new_message(msg);
+		this.on_resize = () => this.term.fit();
+	}
+
+	send(req) {
+		this.props.socket.send(JSON.stringify(req));
+	}
+
+	componentDidMount() {
+		this.term = new XTerm();
+		this.term.open(this.element);
+		this.term.fit();
+		this.term.on("data", (data) => {
+			if (!this.closed) this.send({"TerminalInput": {"id": this.id, "data": data}});
+		});
+		this.term.on("resize", (size) => {
+			if (!this.closed) this.send({"TerminalResize": {"id": this.id, "rows": size.rows, "cols": size.cols}});
+		});
+		window.addEventListener("resize", this.on_resize);
+		this.props.socket.addEventListener("message", this.on_message);
+
+		this.send({"TerminalOpen": {
+			"id": this.id,
+			"path": this.props.path,
+			"environment": null,
+			"rows": this.term.rows,
+			"cols": this.term.cols
+		}});
+		this.term.focus();
+	}
+
+	componentWillUnmount() {
+		if (!this.closed) this.send({"TerminalClose": {"id": this.id}});
+		window.removeEventListener("resize", this.on_resize);
+		this.props.socket.removeEventListener("message", this.on_message);
+		this.term.destroy();
+	}
+
+	new_message(msg) {
+		try {
+			let json_msg = JSON.parse(msg.data);
+			if ("TerminalOutput" in json_msg && json_msg.TerminalOutput.id == this.id) {
+				this.term.write(json_msg.TerminalOutput.data);
+			} else if ("TerminalClosed" in json_msg && json_msg.TerminalClosed.id == this.id) {
+				this.closed = true;
+				this.term.write("\r\n[shell exited]\r\n");
+			} else if ("Error" in json_msg && json_msg.Error.id == this.id) {
+				// the error itself is shown as a notification
+				this.closed = true;
+			}
+		} catch (e) {}
+	}
+
+	render() {
+		return (
+			<div className="terminal-panel">
+				<div ref={(element) => this.element = element} style={{height: "100%"}}></div>
+			</div>
+		);
+	}
+}
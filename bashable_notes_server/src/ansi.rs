@@ -0,0 +1,386 @@
+// Converts terminal output, with its ANSI escape sequences and carriage
+// returns, into HTML. Only SGR sequences (colours, bold, underline, ...) are
+// kept, anything else that moves the cursor or changes the terminal is
+// dropped.
+
+const COLOR_NAMES: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Color {
+    /// One of the 16 standard colours, styled by the client's css
+    Palette(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    /// Reads an extended colour, `5;n` or `2;r;g;b`, from the parameters
+    /// following a 38 or 48.
+    fn extended(params: &mut dyn Iterator<Item = u16>) -> Option<Color> {
+        match params.next()? {
+            5 => {
+                let index = params.next()?.min(255) as u8;
+                Some(Color::indexed(index))
+            }
+            2 => {
+                let r = params.next()?.min(255) as u8;
+                let g = params.next()?.min(255) as u8;
+                let b = params.next()?.min(255) as u8;
+                Some(Color::Rgb(r, g, b))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns one of the 256 colours of xterm.
+    fn indexed(index: u8) -> Color {
+        const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+        match index {
+            0..=15 => Color::Palette(index),
+            16..=231 => {
+                let index = index - 16;
+                Color::Rgb(
+                    LEVELS[(index / 36) as usize],
+                    LEVELS[(index / 6 % 6) as usize],
+                    LEVELS[(index % 6) as usize],
+                )
+            }
+            _ => {
+                let grey = 8 + 10 * (index - 232);
+                Color::Rgb(grey, grey, grey)
+            }
+        }
+    }
+
+    fn class(&self, prefix: &str) -> Option<String> {
+        match *self {
+            Color::Palette(index) if index < 8 => {
+                Some(format!("{}{}", prefix, COLOR_NAMES[index as usize]))
+            }
+            Color::Palette(index) => Some(format!("{}bright-{}", prefix, COLOR_NAMES[index as usize - 8])),
+            Color::Rgb(..) => None,
+        }
+    }
+
+    fn css(&self) -> Option<String> {
+        match *self {
+            Color::Rgb(r, g, b) => Some(format!("#{:02x}{:02x}{:02x}", r, g, b)),
+            Color::Palette(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Style {
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    foreground: Option<Color>,
+    background: Option<Color>,
+}
+
+impl Style {
+    /// Applies the parameters of an SGR sequence, e.g. `1;31` from `\x1b[1;31m`.
+    fn apply(&mut self, params: &str) {
+        // empty parameters are 0, so `\x1b[m` is a reset
+        let mut params = params
+            .split([';', ':'])
+            .map(|param| param.parse::<u16>().unwrap_or(0));
+
+        while let Some(param) = params.next() {
+            match param {
+                0 => *self = Style::default(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                22 => {
+                    self.bold = false;
+                    self.dim = false;
+                }
+                23 => self.italic = false,
+                24 => self.underline = false,
+                30..=37 => self.foreground = Some(Color::Palette((param - 30) as u8)),
+                38 => self.foreground = Color::extended(&mut params),
+                39 => self.foreground = None,
+                40..=47 => self.background = Some(Color::Palette((param - 40) as u8)),
+                48 => self.background = Color::extended(&mut params),
+                49 => self.background = None,
+                90..=97 => self.foreground = Some(Color::Palette((param - 90 + 8) as u8)),
+                100..=107 => self.background = Some(Color::Palette((param - 100 + 8) as u8)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Wraps already escaped `text` in a span with this style.
+    fn span(&self, text: &str) -> String {
+        if *self == Style::default() {
+            return text.to_string();
+        }
+
+        let mut classes = Vec::new();
+        let mut css = Vec::new();
+        if self.bold {
+            classes.push(String::from("ansi-bold"));
+        }
+        if self.dim {
+            classes.push(String::from("ansi-dim"));
+        }
+        if self.italic {
+            classes.push(String::from("ansi-italic"));
+        }
+        if self.underline {
+            classes.push(String::from("ansi-underline"));
+        }
+        if let Some(color) = self.foreground {
+            classes.extend(color.class("ansi-"));
+            css.extend(color.css().map(|color| format!("color: {}", color)));
+        }
+        if let Some(color) = self.background {
+            classes.extend(color.class("ansi-bg-"));
+            css.extend(color.css().map(|color| format!("background-color: {}", color)));
+        }
+
+        let mut attributes = String::new();
+        if !classes.is_empty() {
+            attributes.push_str(&format!(r#" class="{}""#, classes.join(" ")));
+        }
+        if !css.is_empty() {
+            attributes.push_str(&format!(r#" style="{}""#, css.join("; ")));
+        }
+        format!("<span{}>{}</span>", attributes, text)
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Finds the end of the escape sequence at the start of `text`, returning
+/// its length and, for SGR sequences, its parameters. Returns `None` if
+/// `text` ends before the sequence does.
+fn escape_sequence(text: &str) -> Option<(usize, Option<&str>)> {
+    let mut chars = text.char_indices().skip(1);
+    match chars.next()? {
+        // control sequence, ends with a byte in @ to ~
+        (_, '[') => {
+            let (end, last) = chars.find(|&(_, c)| ('@'..='~').contains(&c))?;
+            let params = if last == 'm' { Some(&text[2..end]) } else { None };
+            Some((end + 1, params))
+        }
+        // operating system command, e.g. setting the window title
+        (_, ']') => {
+            let bell = text.find('\x07').map(|end| end + 1);
+            let terminator = text.find("\x1b\\").map(|end| end + 2);
+            let end = match (bell, terminator) {
+                (Some(bell), Some(terminator)) => bell.min(terminator),
+                (end, None) | (None, end) => end?,
+            };
+            Some((end, None))
+        }
+        (index, c) => Some((index + c.len_utf8(), None)),
+    }
+}
+
+/// Renders a line of output, without its newline. Text before a carriage
+/// return is overwritten by the text after it, like a progress bar.
+fn render_line(line: &str, style: &mut Style, colors: bool) -> String {
+    let mut html = String::new();
+    let mut text = String::new();
+
+    let flush = |html: &mut String, text: &mut String, style: &Style| {
+        if !text.is_empty() {
+            let escaped = escape_html(text);
+            if colors {
+                html.push_str(&style.span(&escaped));
+            } else {
+                html.push_str(&escaped);
+            }
+            text.clear();
+        }
+    };
+
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '\x1b' => {
+                let (len, params) = match escape_sequence(rest) {
+                    Some(sequence) => sequence,
+                    // the rest of the sequence hasn't been written yet
+                    None => break,
+                };
+                if let Some(params) = params {
+                    let mut next = style.clone();
+                    next.apply(params);
+                    if next != *style {
+                        flush(&mut html, &mut text, style);
+                        *style = next;
+                    }
+                }
+                rest = &rest[len..];
+            }
+            '\r' => {
+                flush(&mut html, &mut text, style);
+                // a trailing one is from a \r\n, or the text after it hasn't
+                // been written yet
+                if rest.len() > 1 {
+                    html.clear();
+                }
+                rest = &rest[1..];
+            }
+            _ => {
+                text.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    flush(&mut html, &mut text, style);
+
+    html
+}
+
+/// Output converted to HTML as it streams in.
+#[derive(Debug, Clone, Default)]
+pub struct HtmlChunk {
+    /// The lines the chunk finished
+    pub lines: String,
+    /// The line still being written, which replaces the previous chunk's
+    pub partial: String,
+}
+
+/// Converts a stream of output to HTML a line at a time, so a line can be
+/// rewritten by carriage returns until it is finished.
+pub struct Converter {
+    /// Keep colours and other styles, otherwise they are stripped
+    colors: bool,
+    /// Style at the start of `line`
+    style: Style,
+    line: String,
+}
+
+impl Converter {
+    pub fn new(colors: bool) -> Self {
+        Converter {
+            colors,
+            style: Style::default(),
+            line: String::new(),
+        }
+    }
+
+    pub fn push(&mut self, data: &str) -> HtmlChunk {
+        self.line.push_str(data);
+
+        let mut lines = String::new();
+        while let Some(end) = self.line.find('\n') {
+            let line: String = self.line.drain(..=end).collect();
+            lines.push_str(&render_line(&line[..end], &mut self.style, self.colors));
+            lines.push('\n');
+        }
+        // text before a carriage return has been overwritten, so only its
+        // styles are kept, otherwise a progress bar's line grows forever
+        let pending = self.line.strip_suffix('\r').unwrap_or(&self.line);
+        if let Some(end) = pending.rfind('\r') {
+            let overwritten: String = self.line.drain(..=end).collect();
+            render_line(&overwritten, &mut self.style, self.colors);
+        }
+        let partial = render_line(&self.line, &mut self.style.clone(), self.colors);
+
+        HtmlChunk { lines, partial }
+    }
+}
+
+/// Converts all of a block's output to HTML.
+pub fn to_html(text: &str, colors: bool) -> String {
+    let chunk = Converter::new(colors).push(text);
+    chunk.lines + &chunk.partial
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resets_styles() {
+        assert_eq!(
+            to_html("\x1b[1;31mred\x1b[0m plain \x1b[32mgreen\x1b[m plain", true),
+            r#"<span class="ansi-bold ansi-red">red</span> plain <span class="ansi-green">green</span> plain"#
+        );
+    }
+
+    #[test]
+    fn extended_colors() {
+        assert_eq!(
+            to_html("\x1b[38;5;9ma\x1b[38;5;196mb\x1b[48;5;232mc", true),
+            concat!(
+                r#"<span class="ansi-bright-red">a</span>"#,
+                r##"<span style="color: #ff0000">b</span>"##,
+                r##"<span style="color: #ff0000; background-color: #080808">c</span>"##
+            )
+        );
+        assert_eq!(
+            to_html("\x1b[38;2;1;2;3mrgb", true),
+            r##"<span style="color: #010203">rgb</span>"##
+        );
+    }
+
+    #[test]
+    fn strips_colors() {
+        assert_eq!(to_html("\x1b[31m<b>\x1b[0m", false), "&lt;b&gt;");
+    }
+
+    #[test]
+    fn carriage_return_overwrites() {
+        assert_eq!(to_html("10%\r50%\r100%\n", true), "100%\n");
+        assert_eq!(to_html("done\r\n", true), "done\n");
+    }
+
+    #[test]
+    fn progress_bar_doesnt_grow() {
+        let mut converter = Converter::new(true);
+        for percent in 0..100 {
+            let chunk = converter.push(&format!("\x1b[32m{}%\x1b[0m\r", percent));
+            assert_eq!(chunk.partial, format!(r#"<span class="ansi-green">{}%</span>"#, percent));
+        }
+        assert!(converter.line.len() < 20);
+
+        let chunk = converter.push("\x1b[1mdone\n");
+        assert_eq!(chunk.lines, "<span class=\"ansi-bold\">done</span>\n");
+    }
+
+    #[test]
+    fn partial_line_ends_in_multibyte_char() {
+        let mut converter = Converter::new(true);
+        assert_eq!(converter.push("progress █").partial, "progress █");
+        assert_eq!(converter.push("█\r").partial, "progress ██");
+        assert_eq!(converter.push("✓").partial, "✓");
+        assert_eq!(to_html("é", false), "é");
+    }
+
+    #[test]
+    fn escape_split_across_chunks() {
+        let mut converter = Converter::new(true);
+        let chunk = converter.push("a\x1b[3");
+        assert_eq!(chunk.partial, "a");
+        let chunk = converter.push("1mb\n");
+        assert_eq!(chunk.lines, r#"a<span class="ansi-red">b</span>"#.to_string() + "\n");
+    }
+
+    #[test]
+    fn drops_other_sequences() {
+        assert_eq!(to_html("\x1b]0;title\x07\x1b[2Ka\x1b[1Gb", true), "ab");
+    }
+}
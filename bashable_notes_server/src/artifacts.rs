@@ -0,0 +1,73 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use glob;
+use mime_guess;
+
+/// A file produced by a block, matched by one of its `artifacts` patterns.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Artifact {
+    /// Path relative to the notebook directory, which is /home in the
+    /// container
+    pub path: String,
+    pub mime: String,
+    pub size: u64,
+}
+
+/// Turns an `artifacts` pattern, relative to /home, into a pattern for the
+/// files in `dir`.
+fn host_pattern(dir: &Path, pattern: &str) -> io::Result<String> {
+    let relative = pattern
+        .strip_prefix("/home/")
+        .unwrap_or(pattern)
+        .trim_start_matches('/');
+    if relative.split('/').any(|component| component == "..") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("artifact pattern {} leaves /home", pattern),
+        ));
+    }
+
+    glob::Pattern::new(relative)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", pattern, err)))?;
+
+    let escaped = glob::Pattern::escape(&dir.to_string_lossy());
+    Ok(format!("{}/{}", escaped, relative))
+}
+
+/// Checks that `patterns` are valid before a block runs, so a typo doesn't
+/// only show up once it has finished.
+pub fn validate(dir: &Path, patterns: &[String]) -> io::Result<()> {
+    for pattern in patterns {
+        host_pattern(dir, pattern)?;
+    }
+    Ok(())
+}
+
+/// Finds the files in `dir` matching any of `patterns`.
+pub fn collect(dir: &Path, patterns: &[String]) -> io::Result<Vec<Artifact>> {
+    let mut seen = HashSet::new();
+    let mut artifacts = Vec::new();
+
+    for pattern in patterns {
+        let paths = glob::glob(&host_pattern(dir, pattern)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        for path in paths {
+            let path = path.map_err(|err| io::Error::other(err.to_string()))?;
+            let metadata = fs::metadata(&path)?;
+            if !metadata.is_file() || !seen.insert(path.clone()) {
+                continue;
+            }
+
+            let relative = path.strip_prefix(dir).unwrap_or(&path);
+            artifacts.push(Artifact {
+                path: relative.to_string_lossy().into_owned(),
+                mime: mime_guess::from_path(&path).first_or_octet_stream().to_string(),
+                size: metadata.len(),
+            });
+        }
+    }
+
+    Ok(artifacts)
+}
@@ -0,0 +1,83 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+/// Output of a block that isn't UTF-8 text, kept as it was written.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Binary {
+    pub mime: String,
+    /// The bytes, base64 encoded
+    pub data: String,
+    pub size: usize,
+}
+
+impl Binary {
+    fn new(mime: &str, bytes: &[u8]) -> Self {
+        Binary {
+            mime: mime.to_string(),
+            data: BASE64.encode(bytes),
+            size: bytes.len(),
+        }
+    }
+}
+
+/// How much of the start of output is looked at to guess its type.
+const SNIFF_LEN: usize = 1024;
+
+/// Guesses the type of output from its first bytes, for the formats a block
+/// is likely to write to stdout. Returns `None` for anything else.
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    const MAGIC: [(&[u8], &str); 7] = [
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"PK\x03\x04", "application/zip"),
+    ];
+    if let Some(&(_, mime)) = MAGIC.iter().find(|&&(magic, _)| bytes.starts_with(magic)) {
+        return Some(mime);
+    }
+
+    // svg is text, but is better shown as an image
+    let start = String::from_utf8_lossy(&bytes[..bytes.len().min(SNIFF_LEN)]);
+    let start = start.trim_start();
+    if start.starts_with("<svg") || (start.starts_with("<?xml") && start.contains("<svg")) {
+        return Some("image/svg+xml");
+    }
+
+    None
+}
+
+/// Whether streamed output should be kept as it is rather than shown as
+/// text, given the output so far and `data`, the chunk just added to it.
+/// Only the start of the output is sniffed, and only the new chunk checked
+/// for NULs, so checking every chunk stays cheap.
+pub fn is_binary(output: &[u8], data: &[u8]) -> bool {
+    let sniffed = output.len() - data.len() < SNIFF_LEN && sniff(output).is_some();
+    sniffed || data.contains(&0)
+}
+
+/// Turns a block's stdout into text to show, along with the raw bytes if
+/// they aren't plain UTF-8 text. Output that isn't UTF-8 but looks like text
+/// is shown as latin-1, which is what it most often is.
+pub fn decode(bytes: Vec<u8>) -> (String, Option<Binary>) {
+    if let Some(mime) = sniff(&bytes) {
+        return (String::new(), Some(Binary::new(mime, &bytes)));
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(ref text) if text.contains('\0') => {
+            (String::new(), Some(Binary::new("application/octet-stream", text.as_bytes())))
+        }
+        Ok(text) => (text, None),
+        Err(err) => {
+            let bytes = err.into_bytes();
+            if bytes.contains(&0) {
+                return (String::new(), Some(Binary::new("application/octet-stream", &bytes)));
+            }
+            let text = bytes.iter().map(|&byte| byte as char).collect();
+            (text, Some(Binary::new("text/plain", &bytes)))
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+use serde_json;
+use executor::ExecOutput;
+
+/// Outputs of blocks that ran successfully, saved on disk by a hash of
+/// everything that went into running them, so unchanged blocks don't have to
+/// run again every time a notebook is saved.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Opens the cache in `$XDG_CACHE_HOME/bashable_notes/outputs`, or in
+    /// `~/.cache` if XDG_CACHE_HOME isn't set.
+    pub fn open() -> Option<Self> {
+        let cache_home = env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+
+        Some(Cache {
+            dir: cache_home.join("bashable_notes").join("outputs"),
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<ExecOutput> {
+        let file = File::open(self.dir.join(key)).ok()?;
+        match serde_json::from_reader(BufReader::new(file)) {
+            Ok(output) => Some(ExecOutput {
+                cached: true,
+                ..output
+            }),
+            Err(err) => {
+                warn!("ignoring unreadable cached output {}: {}", key, err);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: &str, output: &ExecOutput) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        // written to the side first so a half written output is never read
+        let path = self.dir.join(key);
+        let partial = path.with_extension("partial");
+        fs::write(&partial, serde_json::to_vec(output)?)?;
+        fs::rename(partial, path)
+    }
+}
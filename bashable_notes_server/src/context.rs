@@ -0,0 +1,211 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use glob::{MatchOptions, Pattern};
+use tar;
+
+/// A line of a `.dockerignore` file.
+struct Rule {
+    pattern: Pattern,
+    /// Set for `!` lines, which include files an earlier line excluded
+    exception: bool,
+}
+
+/// The patterns of a build context's `.dockerignore`, which keep files the
+/// image doesn't need (`.git`, datasets, block outputs) from being sent to
+/// the daemon on every build.
+pub struct DockerIgnore {
+    rules: Vec<Rule>,
+}
+
+impl DockerIgnore {
+    /// Reads `.dockerignore` from `context_dir`, ignoring nothing if there
+    /// isn't one.
+    pub fn read(context_dir: &Path) -> io::Result<Self> {
+        match fs::read_to_string(context_dir.join(".dockerignore")) {
+            Ok(contents) => DockerIgnore::parse(&contents),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(DockerIgnore { rules: Vec::new() }),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn parse(contents: &str) -> io::Result<Self> {
+        let mut rules = Vec::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (exception, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => (true, pattern.trim()),
+                None => (false, line),
+            };
+            let pattern = pattern.trim_start_matches('/').trim_end_matches('/');
+            let pattern = Pattern::new(pattern).map_err(|err| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!(".dockerignore: {}: {}", line, err))
+            })?;
+            rules.push(Rule { pattern, exception });
+        }
+        Ok(DockerIgnore { rules })
+    }
+
+    /// Whether `path`, relative to the context directory, is left out of
+    /// the context. A pattern matching a directory matches everything in
+    /// it, and the last matching line wins.
+    pub fn is_ignored(&self, path: &str) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        let matches = |pattern: &Pattern| {
+            let mut prefix = path;
+            loop {
+                if pattern.matches_with(prefix, options) {
+                    return true;
+                }
+                match prefix.rfind('/') {
+                    Some(end) => prefix = &prefix[..end],
+                    None => return false,
+                }
+            }
+        };
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| matches(&rule.pattern))
+            .is_some_and(|rule| !rule.exception)
+    }
+
+    /// Whether any line is an exception, in which case ignored directories
+    /// still have to be searched for the files it includes.
+    pub fn has_exceptions(&self) -> bool {
+        self.rules.iter().any(|rule| rule.exception)
+    }
+}
+
+/// Tars up the build context in `context_dir`, leaving out the files its
+/// `.dockerignore` lists. The Dockerfile and `.dockerignore` are always
+/// sent, as the daemon needs them.
+pub fn build_context(context_dir: &Path, docker_file: &str) -> io::Result<Vec<u8>> {
+    let ignore = DockerIgnore::read(context_dir)?;
+    let mut context = tar::Builder::new(Vec::new());
+    context.follow_symlinks(false);
+    append_dir(&mut context, context_dir, "", &ignore, docker_file)?;
+    context.into_inner()
+}
+
+fn append_dir(
+    context: &mut tar::Builder<Vec<u8>>,
+    dir: &Path,
+    relative: &str,
+    ignore: &DockerIgnore,
+    docker_file: &str,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name();
+        let path = match relative {
+            "" => name.to_string_lossy().into_owned(),
+            _ => format!("{}/{}", relative, name.to_string_lossy()),
+        };
+        let file_type = entry.file_type()?;
+        let ignored = ignore.is_ignored(&path) && path != docker_file && path != ".dockerignore";
+
+        if file_type.is_dir() {
+            // an ignored directory can still have files an exception includes
+            if ignored && !ignore.has_exceptions() {
+                continue;
+            }
+            if !ignored {
+                context.append_dir(&path, entry.path())?;
+            }
+            append_dir(context, &entry.path(), &path, ignore, docker_file)?;
+        } else if !ignored {
+            context.append_path_with_name(entry.path(), &path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn ignore(contents: &str) -> DockerIgnore {
+        DockerIgnore::parse(contents).unwrap()
+    }
+
+    #[test]
+    fn matches_patterns() {
+        let ignore = ignore("# outputs\n\n*.svg\n/data/\nlogs/*.log\n");
+        assert!(ignore.is_ignored("graph.svg"));
+        assert!(!ignore.is_ignored("plots/graph.svg"));
+        assert!(ignore.is_ignored("data"));
+        assert!(ignore.is_ignored("logs/run.log"));
+        assert!(!ignore.is_ignored("logs/old/run.log"));
+        assert!(!ignore.is_ignored("# outputs"));
+        assert!(!ignore.is_ignored("notebook.md"));
+    }
+
+    #[test]
+    fn directories_match_everything_in_them() {
+        let ignore = ignore(".git\ndata\n");
+        assert!(ignore.is_ignored(".git/HEAD"));
+        assert!(ignore.is_ignored("data/raw/part-1.csv"));
+        assert!(!ignore.is_ignored("database.csv"));
+    }
+
+    #[test]
+    fn last_matching_line_wins() {
+        let ignore = ignore("data\n!data/keep\ndata/keep/*.tmp\n");
+        assert!(ignore.has_exceptions());
+        assert!(ignore.is_ignored("data/raw.csv"));
+        assert!(!ignore.is_ignored("data/keep"));
+        assert!(!ignore.is_ignored("data/keep/model.bin"));
+        assert!(ignore.is_ignored("data/keep/model.tmp"));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        match DockerIgnore::parse("[\n") {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("parsed an invalid pattern"),
+        }
+    }
+
+    #[test]
+    fn leaves_ignored_files_out_of_the_context() {
+        let dir = TempDir::new("bashable_notes_test").unwrap();
+        fs::write(dir.path().join("Dockerfile"), "FROM ubuntu\n").unwrap();
+        fs::write(dir.path().join(".dockerignore"), "*\n!requirements.txt\n!data/keep\n").unwrap();
+        fs::write(dir.path().join("requirements.txt"), "numpy\n").unwrap();
+        fs::write(dir.path().join("graph.svg"), "<svg/>").unwrap();
+        fs::create_dir_all(dir.path().join("data/keep")).unwrap();
+        fs::write(dir.path().join("data/raw.csv"), "1,2\n").unwrap();
+        fs::write(dir.path().join("data/keep/model.bin"), "model").unwrap();
+
+        let context = build_context(dir.path(), "Dockerfile").unwrap();
+        let mut archive = tar::Archive::new(&context[..]);
+        let mut paths: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                ".dockerignore",
+                "Dockerfile",
+                "data/keep",
+                "data/keep/model.bin",
+                "requirements.txt",
+            ]
+        );
+    }
+}
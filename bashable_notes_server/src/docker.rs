@@ -1,76 +1,485 @@
-use std::process::Command;
-use std::path::Path;
-use std::io;
+use std::os::unix::net::UnixStream;
+use std::net::Shutdown;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::os::unix::fs::PermissionsExt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{self, Command};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json;
+use sha2::{Digest, Sha256};
+use glob;
+use tempdir::TempDir;
+use context;
+use engine::{self, Engine};
+use executor::{self, ExecOutput, ExecRequest, ExecStatus, Executor, Process, Stream, Terminal};
+
+/// Network an image is built and a container is run in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Network {
+    None,
+    Bridge,
+    Host,
+    /// A user defined docker network
+    Named(String),
+}
+
+impl Network {
+    fn mode(&self) -> String {
+        String::from(self.clone())
+    }
+}
+
+impl From<String> for Network {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "none" => Network::None,
+            "bridge" => Network::Bridge,
+            "host" => Network::Host,
+            _ => Network::Named(name),
+        }
+    }
+}
+
+impl From<Network> for String {
+    fn from(network: Network) -> Self {
+        match network {
+            Network::None => String::from("none"),
+            Network::Bridge => String::from("bridge"),
+            Network::Host => String::from("host"),
+            Network::Named(name) => name,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Image {
     name: String,
 }
 
+/// One message of the json stream returned while building an image.
+#[derive(Deserialize)]
+struct BuildMessage {
+    stream: Option<String>,
+    error: Option<String>,
+    /// Progress of pulling base images
+    status: Option<String>,
+    id: Option<String>,
+    progress: Option<String>,
+}
+
 impl Image {
-    pub fn build(name: &str, docker_file: &Path) -> io::Result<Self> {
+    /// Builds `docker_file` and tags it `name`, passing the build's output to
+    /// `on_log` as it runs. `no_cache` rebuilds every layer instead of reusing
+    /// ones from earlier builds.
+    pub fn build(
+        engine: &Engine,
+        name: &str,
+        docker_file: &Path,
+        network: &Network,
+        no_cache: bool,
+        on_log: &mut dyn FnMut(String),
+    ) -> io::Result<Self> {
         info!(
             "building docker file: {}",
             docker_file.canonicalize()?.to_str().unwrap()
         );
 
-        let output = Command::new("docker")
-            .current_dir(docker_file.parent().unwrap().canonicalize()?.to_str().unwrap())
-            .arg("build")
-            .arg("--network=host") // share the network with host
-            // .arg(docker_file.canonicalize()?.to_str().unwrap())
-            .arg(".")
-            .arg("-t")
-            .arg(name)
-            .output()?;
-
-        let success_message = format!("Successfully tagged {}:latest", name);
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        if !stdout.contains(&success_message) {
-            panic!(
-                "Failed to build docker image: {}",
-                String::from_utf8_lossy(&output.stderr)
-            )
+        // the build context is the Dockerfile's directory, sent as a tarball
+        let context_dir = docker_file.parent().unwrap().canonicalize()?;
+        let file_name = docker_file.file_name().unwrap().to_string_lossy();
+        let context = context::build_context(&context_dir, &file_name)?;
+
+        let path = format!(
+            "/build?t={}&dockerfile={}&networkmode={}&rm=1&nocache={}",
+            engine::encode(name),
+            engine::encode(&file_name),
+            engine::encode(&network.mode()),
+            no_cache as u8
+        );
+        let registry_config = engine::registry_config();
+        let headers = [
+            ("Content-Type", "application/x-tar"),
+            ("X-Registry-Config", registry_config.as_str()),
+        ];
+        let response = engine.request_with_headers("POST", &path, &headers, &context)?;
+
+        for line in BufReader::new(response).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let message: BuildMessage = serde_json::from_str(&line)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            if let Some(error) = message.error {
+                return Err(io::Error::other(format!(
+                    "failed to build docker image: {}",
+                    error
+                )));
+            }
+            if let Some(stream) = message.stream {
+                debug!("docker build: {}", stream.trim_end());
+                on_log(stream);
+            }
+            // skip the many progress updates sent while pulling layers
+            if let (Some(status), None) = (message.status, message.progress) {
+                match message.id {
+                    Some(id) => on_log(format!("{}: {}\n", id, status)),
+                    None => on_log(format!("{}\n", status)),
+                }
+            }
         }
 
         Ok(Image {
             name: name.to_string(),
         })
     }
+
+    /// Uses an image that is already available locally, without pulling it.
+    pub fn existing(engine: &Engine, name: &str) -> io::Result<Self> {
+        match engine.request("GET", &format!("/images/{}/json", name), "application/json", b"") {
+            Ok(_) => Ok(Image {
+                name: name.to_string(),
+            }),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("docker image {} not found, pull or build it first", name),
+            )),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Hashes a Dockerfile along with the files it copies from its build
+/// context, so images only need rebuilding when one of them changes.
+///
+/// Hashing just the copied files, rather than the whole context, stops
+/// files written by blocks into the notebook directory from causing rebuilds.
+/// Files the context's `.dockerignore` leaves out aren't copied, so they
+/// aren't hashed either, which matters for `COPY . /app`.
+pub fn source_hash(docker_file: &Path) -> io::Result<String> {
+    let context_dir = docker_file.parent().unwrap().canonicalize()?;
+    let contents = fs::read_to_string(docker_file)?;
+    let ignore = context::DockerIgnore::read(&context_dir)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(contents.as_bytes());
+    for source in copied_sources(&contents) {
+        // remote ADD sources are only tracked by url
+        if source.contains("://") {
+            hasher.update(source.as_bytes());
+            continue;
+        }
+
+        let pattern = context_dir.join(source.trim_start_matches('/'));
+        let paths = glob::glob(&pattern.to_string_lossy())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mut paths = paths
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| io::Error::other(err.to_string()))?;
+        paths.sort();
+
+        for path in paths {
+            hash_path(&mut hasher, &context_dir, &ignore, &path)?;
+        }
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Adds a file, or everything in a directory, to `hasher`, skipping what
+/// `ignore` leaves out of the build context. Symlinks are hashed by their
+/// target rather than followed, as that is how they are copied.
+fn hash_path(
+    hasher: &mut Sha256,
+    context_dir: &Path,
+    ignore: &context::DockerIgnore,
+    path: &Path,
+) -> io::Result<()> {
+    let name = path.strip_prefix(context_dir).unwrap_or(path).to_string_lossy();
+    let ignored = !name.is_empty() && ignore.is_ignored(&name);
+    let file_type = fs::symlink_metadata(path)?.file_type();
+
+    if file_type.is_dir() {
+        // an ignored directory can still have files an exception includes
+        if ignored && !ignore.has_exceptions() {
+            return Ok(());
+        }
+        let mut entries = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            hash_path(hasher, context_dir, ignore, &entry)?;
+        }
+    } else if !ignored {
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+        if file_type.is_symlink() {
+            hasher.update(fs::read_link(path)?.to_string_lossy().as_bytes());
+        } else {
+            hasher.update(fs::read(path)?);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the sources of the COPY and ADD instructions in a Dockerfile,
+/// skipping those that copy from another build stage.
+fn copied_sources(docker_file: &str) -> Vec<String> {
+    let mut sources = Vec::new();
+    let mut instruction = String::new();
+    for line in docker_file.lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+        // join lines continued with a backslash
+        if let Some(line) = line.strip_suffix('\\') {
+            instruction.push_str(line);
+            instruction.push(' ');
+            continue;
+        }
+        instruction.push_str(line);
+
+        let mut words = instruction.splitn(2, char::is_whitespace);
+        let keyword = words.next().unwrap_or("").to_uppercase();
+        let args = words.next().unwrap_or("").trim();
+        if keyword == "COPY" || keyword == "ADD" {
+            let mut args: Vec<String> = match serde_json::from_str(args) {
+                Ok(args) => args,
+                Err(_) => args.split_whitespace().map(String::from).collect(),
+            };
+            if !args.iter().any(|arg| arg.starts_with("--from")) {
+                args.retain(|arg| !arg.starts_with("--"));
+                args.pop(); // the destination
+                sources.extend(args);
+            }
+        }
+        instruction.clear();
+    }
+    sources
+}
+
+/// Where the image of a notebook environment comes from.
+#[derive(Debug, Clone)]
+pub enum ImageSource {
+    /// Build `docker_file`, with its directory as the build context. The
+    /// image is named `name`, tagged with a hash of its sources, and only
+    /// built when no image with that tag exists unless `rebuild` is set.
+    Build {
+        docker_file: PathBuf,
+        name: String,
+        rebuild: bool,
+    },
+    /// An existing local image
+    Existing(String),
+}
+
+/// Resource limits applied to a container, in the formats `docker run`
+/// accepts (e.g. `"512m"` of memory, `1.5` cpus).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Limits {
+    pub memory: Option<String>,
+    pub cpus: Option<f64>,
+    pub pids_limit: Option<u64>,
+}
+
+impl Limits {
+    /// Memory limit in bytes, from a number with an optional b, k, m or g
+    /// suffix.
+    fn memory_bytes(&self) -> io::Result<Option<i64>> {
+        let memory = match self.memory {
+            Some(ref memory) => memory.trim().to_lowercase(),
+            None => return Ok(None),
+        };
+
+        let (number, unit) = match memory.find(|c: char| c.is_alphabetic()) {
+            Some(index) => memory.split_at(index),
+            None => (memory.as_str(), "b"),
+        };
+        let multiplier = match unit {
+            "b" => 1,
+            "k" | "kb" => 1 << 10,
+            "m" | "mb" => 1 << 20,
+            "g" | "gb" => 1 << 30,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid memory limit: {}", memory),
+                ))
+            }
+        };
+        let number: f64 = number.trim().parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid memory limit: {}", memory),
+            )
+        })?;
+
+        Ok(Some((number * multiplier as f64) as i64))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CreateContainer<'a> {
+    image: &'a str,
+    user: &'a str,
+    open_stdin: bool,
+    labels: HashMap<&'static str, String>,
+    host_config: HostConfig,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct HostConfig {
+    auto_remove: bool,
+    binds: Vec<String>,
+    network_mode: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nano_cpus: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pids_limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CreateExec<'a> {
+    attach_stdin: bool,
+    attach_stdout: bool,
+    attach_stderr: bool,
+    tty: bool,
+    env: Vec<String>,
+    cmd: Vec<&'a str>,
+    /// Overrides the container's user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct StartExec {
+    detach: bool,
+    tty: bool,
+}
+
+/// A container returned by listing containers.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListedContainer {
+    id: String,
+    labels: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Created {
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ExecInspect {
+    exit_code: Option<i64>,
 }
 
 #[derive(Clone)]
 pub struct Container {
+    engine: Engine,
     id: String,
     image: Image,
+    limits: Limits,
+    /// Host directory the code of running blocks is written to, mounted
+    /// read only at `CODE_DIR`
+    code_dir: Arc<TempDir>,
+    /// Home directory of the user blocks run as, unless they keep the
+    /// image's
+    home: Option<&'static str>,
+    paused: Arc<AtomicBool>,
+    killed: Arc<AtomicBool>,
 }
 
 impl Container {
-    pub fn start(image: Image, home_path: &Path) -> io::Result<Self> {
-        let mut command = Command::new("docker");
-        let command = command
-            .arg("run")
-            .arg("-i") // keep container alive even though we are not attached
-            .arg("-d") // run in the background
-            .arg("-v") // link notebook folder
-            .arg(format!("{}:/home", home_path.canonicalize()?.to_str().unwrap()))
-            .arg("--net=host") // share the network with host
-            .arg(&image.name);
+    /// Starts a container for the notebook at `notebook`, labelled so it can
+    /// be found again if the server dies without stopping it. Blocks run as
+    /// `user`, e.g. `"1000:1000"`, with a home directory of their own.
+    pub fn start(
+        engine: &Engine,
+        image: Image,
+        notebook: &Path,
+        home_path: &Path,
+        limits: &Limits,
+        network: &Network,
+        user: &str,
+    ) -> io::Result<Self> {
+        let mut labels = HashMap::new();
+        labels.insert(INSTANCE_LABEL, instance().to_string());
+        labels.insert(PID_LABEL, process::id().to_string());
+        labels.insert(NOTEBOOK_LABEL, notebook.to_string_lossy().into_owned());
 
-        debug!("docker run command: {:?}", command);
-        let output = command.output()?;
+        // readable by the container's user, whoever runs the server
+        let code_dir = TempDir::new("bashable_notes_code")?;
+        fs::set_permissions(code_dir.path(), fs::Permissions::from_mode(0o755))?;
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let stderr = String::from_utf8_lossy(&output.stderr);
+        let create = CreateContainer {
+            image: &image.name,
+            user,
+            open_stdin: true, // keep container alive even though we are not attached
+            labels,
+            host_config: HostConfig {
+                auto_remove: true, // killed containers are removed
+                binds: vec![
+                    // link notebook folder
+                    format!("{}:/home", home_path.canonicalize()?.to_str().unwrap()),
+                    format!("{}:{}:ro", code_dir.path().to_str().unwrap(), CODE_DIR),
+                ],
+                network_mode: network.mode(),
+                memory: limits.memory_bytes()?,
+                nano_cpus: limits.cpus.map(|cpus| (cpus * 1e9) as i64),
+                pids_limit: limits.pids_limit.map(|pids_limit| pids_limit as i64),
+            },
+        };
 
-        if stderr != "" {
-            panic!("Failed to start container: {}", stderr);
-        }
+        let created: Created = engine.post_json("/containers/create", &create)?;
+        engine.post(&format!("/containers/{}/start", created.id))?;
 
-        Ok(Container {
-            id: stdout.trim().to_string(),
+        // root, or the image's own user if none is given, keep the image's home
+        let keeps_home = matches!(user.split(':').next(), Some("root") | Some("0") | Some(""));
+        let container = Container {
+            engine: engine.clone(),
+            id: created.id,
             image,
-        })
+            limits: limits.clone(),
+            code_dir: Arc::new(code_dir),
+            home: if keeps_home { None } else { Some(HOME_DIR) },
+            paused: Arc::new(AtomicBool::new(false)),
+            killed: Arc::new(AtomicBool::new(false)),
+        };
+
+        // the user likely has no home in the image, or one they can't write to
+        if container.home.is_some() {
+            let create_home = format!("mkdir -p {0} && chown {1} {0}", HOME_DIR, user);
+            if let Err(err) = container.run_as_root(&create_home) {
+                container.kill()?;
+                return Err(io::Error::other(format!("unable to create a home for {}: {}", user, err)));
+            }
+        }
+
+        Ok(container)
     }
 
     pub fn id(&self) -> String {
@@ -79,39 +488,546 @@ impl Container {
 
     pub fn kill(self) -> io::Result<()> {
         info!("killing container: {}", self.id);
+        self.killed.store(true, Ordering::SeqCst);
+
+        match self.engine.post(&format!("/containers/{}/kill", self.id)) {
+            Ok(()) => info!("container killed"),
+            Err(err) => warn!("failed to kill container: {}", err),
+        }
+
+        Ok(())
+    }
+
+    pub fn pause(&self) -> io::Result<()> {
+        info!("pausing container: {}", self.id);
+        self.engine.post(&format!("/containers/{}/pause", self.id))?;
+        self.paused.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn unpause(&self) -> io::Result<()> {
+        info!("unpausing container: {}", self.id);
+        self.engine.post(&format!("/containers/{}/unpause", self.id))?;
+        self.paused.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.killed.load(Ordering::SeqCst)
+    }
+
+    /// Creates an exec running `cmd` with bash, returning its id. `stdin`
+    /// attaches the exec's stdin to the stream returned by `start_exec`, and
+    /// `tty` runs it in a pseudo terminal.
+    fn create_exec(&self, cmd: &str, mut env: Vec<String>, stdin: bool, tty: bool) -> io::Result<String> {
+        if let Some(home) = self.home {
+            env.insert(0, format!("HOME={}", home));
+        }
+        let exec = CreateExec {
+            attach_stdin: stdin,
+            attach_stdout: true,
+            attach_stderr: true,
+            tty,
+            env,
+            cmd: vec!["bash", "-c", cmd],
+            user: None,
+        };
+        let created: Created = self
+            .engine
+            .post_json(&format!("/containers/{}/exec", self.id), &exec)?;
+        Ok(created.id)
+    }
+
+    /// Starts an exec created by `create_exec`, returning its output stream.
+    /// The output is multiplexed unless the exec has a `tty`, which merges
+    /// stdout and stderr.
+    fn start_exec(&self, exec_id: &str, tty: bool) -> io::Result<BufReader<UnixStream>> {
+        let start = StartExec { detach: false, tty };
+        self.engine
+            .attach(&format!("/exec/{}/start", exec_id), &start)
+    }
 
-        let output = Command::new("docker").arg("kill").arg(self.id).output()?;
+    fn exit_code(&self, exec_id: &str) -> io::Result<Option<i64>> {
+        let inspect: ExecInspect = self.engine.get(&format!("/exec/{}/json", exec_id))?;
+        Ok(inspect.exit_code)
+    }
+
+    /// Runs a bash command to completion, failing with its stderr if it
+    /// exits unsuccessfully.
+    fn run(&self, cmd: &str) -> io::Result<String> {
+        let exec_id = self.create_exec(cmd, Vec::new(), false, false)?;
+        self.wait_for(&exec_id)
+    }
 
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        if stderr != "" {
-            warn!("failed to kill container: {}", stderr);
+    /// Like `run`, but as root instead of the container's user.
+    fn run_as_root(&self, cmd: &str) -> io::Result<String> {
+        let exec = CreateExec {
+            attach_stdin: false,
+            attach_stdout: true,
+            attach_stderr: true,
+            tty: false,
+            env: Vec::new(),
+            cmd: vec!["bash", "-c", cmd],
+            user: Some("root"),
+        };
+        let created: Created = self
+            .engine
+            .post_json(&format!("/containers/{}/exec", self.id), &exec)?;
+        self.wait_for(&created.id)
+    }
+
+    /// Waits for an exec started by `run` to finish, returning its stdout.
+    fn wait_for(&self, exec_id: &str) -> io::Result<String> {
+        let (stdout, stderr) = engine::demultiplex(self.start_exec(exec_id, false)?)?;
+
+        if self.exit_code(exec_id)? != Some(0) {
+            let stderr = String::from_utf8_lossy(&stderr);
+            return Err(io::Error::other(stderr.trim().to_string()));
+        }
+        Ok(String::from_utf8_lossy(&stdout).into_owned())
+    }
+
+    /// Number of processes the kernel has killed for exceeding the container's
+    /// memory limit, read from the cgroup v2 or v1 memory controller.
+    fn oom_kills(&self) -> io::Result<u64> {
+        let events = self.run(
+            "cat /sys/fs/cgroup/memory.events /sys/fs/cgroup/memory/memory.oom_control 2>/dev/null; true",
+        )?;
+
+        Ok(events
+            .lines()
+            .filter_map(|line| line.strip_prefix("oom_kill "))
+            .filter_map(|count| count.trim().parse::<u64>().ok())
+            .sum())
+    }
+
+    /// Kills the process group of the exec named `name` by `exec_name`,
+    /// including anything the command spawned.
+    fn kill_exec(&self, name: &str) -> io::Result<()> {
+        let pid = format!("$(cat {})", pid_file(name));
+        self.run(&format!("kill -KILL -- -{0} 2>/dev/null || kill -KILL {0}", pid))?;
+        Ok(())
+    }
+
+    pub fn exec(
+        &self,
+        request: &ExecRequest,
+        cancelled: &dyn Fn() -> bool,
+        on_output: &mut dyn FnMut(Stream, String),
+    ) -> io::Result<ExecOutput> {
+        // only containers with a memory limit can have blocks killed for it
+        let oom_kills = match self.limits.memory {
+            Some(_) => Some(self.oom_kills()?),
+            None => None,
+        };
+
+        // the code is written to a file only this exec uses, as the same
+        // block can be running for two renders of a notebook
+        let name = executor::exec_name(request.id);
+        let code_file = name.clone();
+        let host_code_file = self.code_dir.path().join(&code_file);
+        fs::write(&host_code_file, request.code)?;
+
+        let cmd = format!(
+            "echo $$ > {} && cd home && {}",
+            pid_file(&name),
+            request.cmd
+        );
+        let mut env: Vec<_> = request
+            .env
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        if let Some(code) = executor::code_env(request) {
+            env.push(format!("CODE={}", code));
+        }
+        env.push(format!("CODE_FILE={}/{}", CODE_DIR, code_file));
+        let exec_id = self.create_exec(&cmd, env, true, false)?;
+        debug!("docker exec {}: {}", exec_id, cmd);
+
+        let reader = self.start_exec(&exec_id, false)?;
+        let mut stdin = reader.get_ref().try_clone()?;
+        let mut input = None;
+        if request.input.is_some() {
+            input = Some(stdin);
         } else {
-            info!("container killed");
+            let code = request.code.to_string();
+            thread::spawn(move || {
+                // commands that don't read their stdin close it early
+                if let Err(err) = stdin
+                    .write_all(code.as_bytes())
+                    .and_then(|_| stdin.shutdown(Shutdown::Write))
+                {
+                    debug!("unable to write code to stdin: {}", err);
+                }
+            });
+        }
+
+        let (tx, rx) = channel();
+        engine::read_multiplexed(reader, tx);
+
+        let mut output = executor::run_streaming(
+            &mut |timeout| rx.recv_timeout(timeout),
+            request,
+            cancelled,
+            on_output,
+            &|| {
+                // a paused container can't run the kill, so rather than leave
+                // the block running the whole container goes
+                self.kill_exec(&name).or_else(|err| {
+                    warn!("unable to kill block {} ({}), killing its container", request.id, err);
+                    self.clone().kill()
+                })
+            },
+            &|| self.is_paused(),
+            input.as_mut().map(|stdin| stdin as &mut dyn Write),
+        );
+        let exit_code = self.exit_code(&exec_id)?;
+        debug!("block {} exited with {:?}", request.id, exit_code);
+        if let Some(code) = exit_code {
+            let (exit_code, signal) = executor::split_exit_code(code as i32);
+            output.exit_code = exit_code;
+            output.signal = signal;
+        }
+        if let Err(err) = fs::remove_file(&host_code_file) {
+            warn!("unable to remove {:?}: {}", host_code_file, err);
+        }
+
+        // the count is for the whole container, so with blocks running at the
+        // same time only one that was itself killed is blamed for it
+        if let Some(before) = oom_kills {
+            let killed = output.signal == Some(9);
+            if output.status == ExecStatus::Completed && killed && self.oom_kills()? > before {
+                warn!("block {} was killed while the container was over its memory limit", request.id);
+                output.status = ExecStatus::OutOfMemory;
+            }
+        }
+
+        Ok(output)
+    }
+
+    pub fn spawn(&self, id: &str, cmd: &str, env: &[(&str, &str)]) -> io::Result<Process> {
+        let name = executor::exec_name(id);
+        let cmd = format!("echo $$ > {} && cd home && {}", pid_file(&name), cmd);
+        let env = env
+            .iter()
+            .map(|&(name, value)| format!("{}={}", name, value))
+            .collect();
+        let exec_id = self.create_exec(&cmd, env, true, false)?;
+        debug!("docker exec {}: {}", exec_id, cmd);
+
+        let reader = self.start_exec(&exec_id, false)?;
+        let stdin = reader.get_ref().try_clone()?;
+        let (tx, rx) = channel();
+        engine::read_multiplexed(reader, tx);
+
+        let container = self.clone();
+        Ok(Process {
+            stdin: Box::new(stdin),
+            output: rx,
+            kill: Box::new(move || container.kill_exec(&name)),
+        })
+    }
+
+    /// Starts bash in a pseudo terminal in the notebook directory.
+    pub fn terminal(&self, rows: u16, cols: u16) -> io::Result<Terminal> {
+        let id = executor::exec_name("terminal");
+
+        let cmd = format!("echo $$ > {} && cd home && exec bash -i", pid_file(&id));
+        let env = vec![String::from("TERM=xterm-256color")];
+        let exec_id = self.create_exec(&cmd, env, true, true)?;
+        debug!("docker exec {}: {}", exec_id, cmd);
+
+        let reader = self.start_exec(&exec_id, true)?;
+        let stdin = reader.get_ref().try_clone()?;
+        let connection = reader.get_ref().try_clone()?;
+        let (tx, rx) = channel();
+        executor::read_stream(Stream::Stdout, reader, tx);
+
+        // the exec has to be running before it can be resized
+        let engine = self.engine.clone();
+        let resize = move |rows: u16, cols: u16| {
+            engine.post(&format!("/exec/{}/resize?h={}&w={}", exec_id, rows, cols))
+        };
+        if let Err(err) = resize(rows, cols) {
+            warn!("unable to resize terminal {}: {}", id, err);
+        }
+
+        let container = self.clone();
+        Ok(Terminal {
+            process: Process {
+                stdin: Box::new(stdin),
+                output: rx,
+                kill: Box::new(move || {
+                    let killed = container.kill_exec(&id);
+                    // closing the connection hangs up anything the kill missed
+                    connection.shutdown(Shutdown::Both).and(killed)
+                }),
+            },
+            resize: Box::new(resize),
+        })
+    }
+}
+
+const INSTANCE_LABEL: &str = "bashable_notes.instance";
+const PID_LABEL: &str = "bashable_notes.pid";
+const NOTEBOOK_LABEL: &str = "bashable_notes.notebook";
+
+/// Identifies this run of the server in the labels of its containers.
+fn instance() -> &'static str {
+    static INSTANCE: OnceLock<String> = OnceLock::new();
+    INSTANCE.get_or_init(|| {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos())
+            .unwrap_or(0);
+        format!("{}-{:x}", process::id(), nanos)
+    })
+}
+
+fn is_running(pid: &str) -> bool {
+    Command::new("ps")
+        .arg("-p")
+        .arg(pid)
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(true)
+}
+
+/// Removes containers left behind by servers that exited without stopping
+/// them, i.e. those labelled with the pid of a process that is no longer
+/// running.
+pub fn remove_stale_containers(engine: &Engine) -> io::Result<()> {
+    let filters = format!(r#"{{"label":["{}"]}}"#, INSTANCE_LABEL);
+    let containers: Vec<ListedContainer> = engine.get(&format!(
+        "/containers/json?all=1&filters={}",
+        engine::encode(&filters)
+    ))?;
+
+    for container in containers {
+        let label = |name| container.labels.get(name).map(String::as_str);
+        if label(INSTANCE_LABEL) == Some(instance()) || label(PID_LABEL).is_some_and(is_running) {
+            continue;
         }
 
+        info!(
+            "removing stale container {} of {}",
+            container.id,
+            label(NOTEBOOK_LABEL).unwrap_or("unknown notebook")
+        );
+        let path = format!("/containers/{}?force=1", container.id);
+        if let Err(err) = engine.request("DELETE", &path, "application/json", b"") {
+            warn!("failed to remove container {}: {}", container.id, err);
+        }
+    }
+    Ok(())
+}
+
+/// Runs notebooks inside a docker container, built from the notebook's
+/// `Dockerfile` unless another image is chosen.
+pub struct DockerExecutor {
+    engine: Engine,
+    notebook_dir: PathBuf,
+    notebook: PathBuf,
+    source: ImageSource,
+    network: Network,
+    limits: Limits,
+    user: String,
+    image: Mutex<Option<Image>>,
+    container: Mutex<Option<Container>>,
+}
+
+impl DockerExecutor {
+    pub fn new(
+        notebook_dir: &Path,
+        notebook: &Path,
+        source: ImageSource,
+        network: Network,
+        limits: Limits,
+        user: String,
+    ) -> Self {
+        DockerExecutor {
+            engine: Engine::new(),
+            notebook_dir: notebook_dir.to_path_buf(),
+            notebook: notebook.to_path_buf(),
+            source,
+            network,
+            limits,
+            user,
+            image: Mutex::new(None),
+            container: Mutex::new(None),
+        }
+    }
+
+    fn build_image(
+        &self,
+        tag: &str,
+        docker_file: &Path,
+        no_cache: bool,
+        on_log: &mut dyn FnMut(String),
+    ) -> io::Result<Image> {
+        info!("building docker image {}", tag);
+        let image = Image::build(&self.engine, tag, docker_file, &self.network, no_cache, on_log)?;
+        info!("docker image built");
+        Ok(image)
+    }
+
+    fn container(&self) -> io::Result<Container> {
+        self.container
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(executor::not_running)
+    }
+}
+
+impl Executor for DockerExecutor {
+    fn build(&self, on_log: &mut dyn FnMut(String)) -> io::Result<()> {
+        let image = match self.source {
+            ImageSource::Build {
+                ref docker_file,
+                ref name,
+                rebuild,
+            } => {
+                if !docker_file.as_path().exists() {
+                    // only the notebook's own Dockerfile gets a default
+                    if *docker_file != self.notebook_dir.join("Dockerfile") {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("{} not found", docker_file.display()),
+                        ));
+                    }
+                    info!("no Dockerfile, creating default Dockerfile");
+
+                    let mut f = File::create(docker_file)?;
+                    f.write_all(b"FROM ubuntu:latest")?;
+                    f.sync_all()?;
+
+                    info!("created default Dockerfile");
+                }
+
+                let tag = format!("{}:{}", name, &source_hash(docker_file)?[..12]);
+                match Image::existing(&self.engine, &tag) {
+                    Ok(image) if !rebuild => {
+                        info!("docker image {} is up to date", tag);
+                        image
+                    }
+                    Ok(_) => self.build_image(&tag, docker_file, true, on_log)?,
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                        self.build_image(&tag, docker_file, false, on_log)?
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            ImageSource::Existing(ref name) => {
+                info!("using docker image {}", name);
+                Image::existing(&self.engine, name)?
+            }
+        };
+
+        *self.image.lock().unwrap() = Some(image);
         Ok(())
     }
 
-    pub fn exec(&self, cmd: &str, code: &str) -> io::Result<(String, String)> {
-        let mut command = Command::new("docker");
-        let command = command
-            .arg("exec")
-            .arg("--env")
-            .arg(&format!("CODE={}", code))
-            .arg(&self.id)
-            .arg("bash")
-            .arg("-c")
-            .arg(&format!("cd home && {}", cmd));
-        // .output()?;
+    fn start(&self) -> io::Result<()> {
+        let image = self.image.lock().unwrap().clone().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "docker image has not been built")
+        })?;
+
+        info!("starting docker container");
+        let container = Container::start(
+            &self.engine,
+            image,
+            &self.notebook,
+            &self.notebook_dir,
+            &self.limits,
+            &self.network,
+            &self.user,
+        )?;
+        info!("docker container {} started", container.id());
+
+        *self.container.lock().unwrap() = Some(container);
+        Ok(())
+    }
+
+    fn exec(
+        &self,
+        request: &ExecRequest,
+        cancelled: &dyn Fn() -> bool,
+        on_output: &mut dyn FnMut(Stream, String),
+    ) -> io::Result<ExecOutput> {
+        self.container()?.exec(request, cancelled, on_output)
+    }
+
+    fn spawn(&self, id: &str, cmd: &str, env: &[(&str, &str)]) -> io::Result<Process> {
+        self.container()?.spawn(id, cmd, env)
+    }
+
+    fn terminal(&self, rows: u16, cols: u16) -> io::Result<Terminal> {
+        self.container()?.terminal(rows, cols)
+    }
+
+    fn pause(&self) -> io::Result<()> {
+        self.container()?.pause()
+    }
+
+    fn resume(&self) -> io::Result<()> {
+        self.container()?.unpause()
+    }
+
+    fn is_paused(&self) -> bool {
+        self.container().is_ok_and(|container| container.is_paused())
+    }
+
+    fn kill(&self) -> io::Result<()> {
+        match self.container.lock().unwrap().take() {
+            Some(container) => container.kill(),
+            None => Ok(()),
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.container().is_ok_and(|container| container.is_running())
+    }
+}
+
+/// Home directory of the user blocks run as, unless it is root.
+const HOME_DIR: &str = "/tmp/bashable_notes_home";
+
+/// Where the code of running blocks is mounted in containers.
+const CODE_DIR: &str = "/tmp/bashable_notes_code";
+
+/// File the pid of the exec `name` is written to, so it can be killed.
+fn pid_file(name: &str) -> String {
+    format!("/tmp/bashable_notes_{}.pid", name)
+}
 
-        debug!("docker exec command: {:?}", command);
-        let output = command.output()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let stderr = String::from_utf8_lossy(&output.stderr);
+    #[test]
+    fn source_hash_skips_ignored_files() {
+        let dir = TempDir::new("bashable_notes_test").unwrap();
+        let docker_file = dir.path().join("Dockerfile");
+        fs::write(&docker_file, "FROM ubuntu\nCOPY . /app\n").unwrap();
+        fs::write(dir.path().join(".dockerignore"), "*.svg\n.git\n").unwrap();
+        fs::write(dir.path().join("requirements.txt"), "numpy\n").unwrap();
+        fs::create_dir(dir.path().join(".git")).unwrap();
+        symlink(dir.path(), dir.path().join("loop")).unwrap();
+        let hash = source_hash(&docker_file).unwrap();
 
-        debug!("docker exec output: {} {}", stdout, stderr);
+        // written by blocks, or ignored
+        fs::write(dir.path().join("graph.svg"), "<svg/>").unwrap();
+        fs::write(dir.path().join(".git").join("HEAD"), "ref: refs/heads/master").unwrap();
+        assert_eq!(source_hash(&docker_file).unwrap(), hash);
 
-        Ok((stdout.to_string(), stderr.to_string()))
+        fs::write(dir.path().join("requirements.txt"), "numpy\npandas\n").unwrap();
+        assert_ne!(source_hash(&docker_file).unwrap(), hash);
     }
 }
@@ -0,0 +1,548 @@
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::env;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use base64::Engine as _;
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE as BASE64_URL};
+use executor::Stream;
+
+const API_VERSION: &str = "v1.41";
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+/// Minimal client for the Docker Engine HTTP API, spoken over the daemon's
+/// unix socket.
+#[derive(Debug, Clone)]
+pub struct Engine {
+    socket: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct ErrorMessage {
+    message: String,
+}
+
+impl Engine {
+    /// Connects to the socket named by DOCKER_HOST, or the default socket.
+    pub fn new() -> Self {
+        let socket = match env::var("DOCKER_HOST") {
+            Ok(ref host) if host.starts_with("unix://") => PathBuf::from(&host[7..]),
+            Ok(host) => {
+                warn!("unsupported DOCKER_HOST {}, using {}", host, DEFAULT_SOCKET);
+                PathBuf::from(DEFAULT_SOCKET)
+            }
+            Err(_) => PathBuf::from(DEFAULT_SOCKET),
+        };
+
+        Engine { socket }
+    }
+
+    /// Sends a request, returning the response status and a reader
+    /// positioned at the start of the body.
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<(u16, HashMap<String, String>, BufReader<UnixStream>)> {
+        let mut stream = UnixStream::connect(&self.socket)?;
+
+        let mut request = format!(
+            "{} /{}{} HTTP/1.1\r\nHost: docker\r\nContent-Length: {}\r\n",
+            method,
+            API_VERSION,
+            path,
+            body.len()
+        );
+        for &(name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+
+        debug!("docker api request: {} {}", method, path);
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(body)?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line)?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| invalid_data(format!("bad status line: {}", status_line)))?;
+
+        let mut response_headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some(colon) = line.find(':') {
+                response_headers.insert(
+                    line[..colon].trim().to_lowercase(),
+                    line[colon + 1..].trim().to_string(),
+                );
+            }
+        }
+
+        Ok((status, response_headers, reader))
+    }
+
+    /// Sends a request and returns the response body, failing with the
+    /// daemon's error message on an error status.
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        content_type: &str,
+        body: &[u8],
+    ) -> io::Result<Box<dyn Read + Send>> {
+        self.request_with_headers(method, path, &[("Content-Type", content_type)], body)
+    }
+
+    /// Like `request`, with extra headers such as registry credentials.
+    pub fn request_with_headers(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<Box<dyn Read + Send>> {
+        let mut headers = headers.to_vec();
+        headers.push(("Connection", "close"));
+        let (status, headers, reader) = self.send(method, path, &headers, body)?;
+
+        let mut body: Box<dyn Read + Send> =
+            if headers.get("transfer-encoding").map(String::as_str) == Some("chunked") {
+                Box::new(Chunked::new(reader))
+            } else if let Some(length) = headers.get("content-length") {
+                let length = length.parse().map_err(invalid_data)?;
+                Box::new(reader.take(length))
+            } else {
+                Box::new(reader)
+            };
+
+        if status >= 400 {
+            let mut text = String::new();
+            body.read_to_string(&mut text)?;
+            let message = serde_json::from_str::<ErrorMessage>(&text)
+                .map(|error| error.message)
+                .unwrap_or(text);
+            let kind = match status {
+                404 => io::ErrorKind::NotFound,
+                _ => io::ErrorKind::Other,
+            };
+            return Err(io::Error::new(kind, message));
+        }
+
+        Ok(body)
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, path: &str) -> io::Result<T> {
+        let body = self.request("GET", path, "application/json", b"")?;
+        serde_json::from_reader(body).map_err(invalid_data)
+    }
+
+    /// Posts an empty body, ignoring any response body.
+    pub fn post(&self, path: &str) -> io::Result<()> {
+        io::copy(
+            &mut self.request("POST", path, "application/json", b"")?,
+            &mut io::sink(),
+        )?;
+        Ok(())
+    }
+
+    /// Posts `body` as json and parses the json response.
+    pub fn post_json<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> io::Result<T> {
+        let body = serde_json::to_vec(body).map_err(invalid_data)?;
+        let response = self.request("POST", path, "application/json", &body)?;
+        serde_json::from_reader(response).map_err(invalid_data)
+    }
+
+    /// Posts `body` as json to an endpoint that hijacks the connection, such
+    /// as starting an exec, returning the raw stream.
+    pub fn attach<B: Serialize>(&self, path: &str, body: &B) -> io::Result<BufReader<UnixStream>> {
+        let body = serde_json::to_vec(body).map_err(invalid_data)?;
+        let headers = [
+            ("Content-Type", "application/json"),
+            ("Connection", "Upgrade"),
+            ("Upgrade", "tcp"),
+        ];
+        let (status, _, mut reader) = self.send("POST", path, &headers, &body)?;
+
+        if status >= 400 {
+            let mut text = String::new();
+            reader.read_to_string(&mut text)?;
+            return Err(io::Error::other(text.trim().to_string()));
+        }
+
+        Ok(reader)
+    }
+}
+
+/// The docker CLI's config file, only the parts holding registry
+/// credentials.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct CliConfig {
+    #[serde(default)]
+    auths: HashMap<String, CliAuth>,
+    creds_store: Option<String>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Default)]
+struct CliAuth {
+    /// base64 encoded `username:password`
+    auth: Option<String>,
+    identitytoken: Option<String>,
+}
+
+/// Credentials for one registry, as the daemon expects them.
+#[derive(Serialize)]
+struct AuthConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identitytoken: Option<String>,
+    serveraddress: String,
+}
+
+/// What a docker credential helper returns for a registry.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+/// Asks the credential helper `docker-credential-<helper>` for the
+/// credentials of `registry`.
+fn helper_credentials(helper: &str, registry: &str) -> io::Result<AuthConfig> {
+    let mut child = Command::new(format!("docker-credential-{}", helper))
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(registry.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stdout).trim().to_string()));
+    }
+
+    let credentials: HelperCredentials = serde_json::from_slice(&output.stdout).map_err(invalid_data)?;
+    // helpers return identity tokens with this as the username
+    Ok(if credentials.username == "<token>" {
+        AuthConfig {
+            username: None,
+            password: None,
+            identitytoken: Some(credentials.secret),
+            serveraddress: registry.to_string(),
+        }
+    } else {
+        AuthConfig {
+            username: Some(credentials.username),
+            password: Some(credentials.secret),
+            identitytoken: None,
+            serveraddress: registry.to_string(),
+        }
+    })
+}
+
+/// Returns the `X-Registry-Config` header for builds: the credentials of
+/// every registry the user has logged in to with `docker login`, read from
+/// `$DOCKER_CONFIG/config.json` (`~/.docker/config.json` by default) and
+/// its credential helpers, so `FROM` can use private images.
+pub fn registry_config() -> String {
+    let path = env::var_os("DOCKER_CONFIG")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".docker")))
+        .map(|dir| dir.join("config.json"));
+    let config: CliConfig = match path.as_ref().map(fs::read) {
+        Some(Ok(contents)) => serde_json::from_slice(&contents).unwrap_or_else(|err| {
+            warn!("unable to parse {:?}: {}", path, err);
+            CliConfig::default()
+        }),
+        _ => CliConfig::default(),
+    };
+    encode_auths(&config)
+}
+
+/// Collects the credentials of every registry in `config`, encoded as the
+/// daemon expects them in `X-Registry-Config`.
+fn encode_auths(config: &CliConfig) -> String {
+    let mut registries: Vec<&String> = config.auths.keys().chain(config.cred_helpers.keys()).collect();
+    registries.sort();
+    registries.dedup();
+
+    let mut auths = HashMap::new();
+    for registry in registries {
+        let helper = config.cred_helpers.get(registry).or(config.creds_store.as_ref());
+        let stored = config.auths.get(registry);
+        let auth = match (stored, helper) {
+            (Some(&CliAuth { auth: Some(ref auth), ref identitytoken }), _) => {
+                let decoded = BASE64.decode(auth).ok().and_then(|auth| String::from_utf8(auth).ok());
+                let (username, password) = match decoded.as_ref().and_then(|auth| auth.split_once(':')) {
+                    Some((username, password)) => (username.to_string(), password.to_string()),
+                    None => {
+                        warn!("unable to decode the credentials of {}", registry);
+                        continue;
+                    }
+                };
+                AuthConfig {
+                    username: Some(username),
+                    password: Some(password),
+                    identitytoken: identitytoken.clone(),
+                    serveraddress: registry.clone(),
+                }
+            }
+            (_, Some(helper)) => match helper_credentials(helper, registry) {
+                Ok(auth) => auth,
+                Err(err) => {
+                    warn!("unable to get credentials for {} from {}: {}", registry, helper, err);
+                    continue;
+                }
+            },
+            _ => continue,
+        };
+        auths.insert(registry.clone(), auth);
+    }
+
+    BASE64_URL.encode(serde_json::to_vec(&auths).unwrap_or_default())
+}
+
+/// Percent encodes a query string value.
+pub fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// Reads a response body sent with chunked transfer encoding.
+struct Chunked<R> {
+    inner: R,
+    remaining: usize,
+    done: bool,
+}
+
+impl<R: BufRead> Chunked<R> {
+    fn new(inner: R) -> Self {
+        Chunked {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.remaining == 0 {
+            let mut line = String::new();
+            self.inner.read_line(&mut line)?;
+            let size = line.trim().split(';').next().unwrap_or("");
+            self.remaining = usize::from_str_radix(size, 16).map_err(invalid_data)?;
+            if self.remaining == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let max = buf.len().min(self.remaining);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        self.remaining -= n;
+        if self.remaining == 0 {
+            let mut crlf = [0; 2];
+            self.inner.read_exact(&mut crlf)?;
+        }
+
+        Ok(n)
+    }
+}
+
+/// Reads one frame of an exec's multiplexed output, returning `None` at the
+/// end of the stream.
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<(Stream, Vec<u8>)>> {
+    let mut header = [0; 8];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    let stream = match header[0] {
+        2 => Stream::Stderr,
+        _ => Stream::Stdout,
+    };
+    let size = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let mut payload = vec![0; size as usize];
+    reader.read_exact(&mut payload)?;
+
+    Ok(Some((stream, payload)))
+}
+
+/// Reads an exec's multiplexed output to the end, returning stdout and
+/// stderr.
+pub fn demultiplex<R: Read>(mut reader: R) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    while let Some((stream, payload)) = read_frame(&mut reader)? {
+        match stream {
+            Stream::Stdout => stdout.extend(payload),
+            Stream::Stderr => stderr.extend(payload),
+        }
+    }
+    Ok((stdout, stderr))
+}
+
+/// Forwards an exec's multiplexed output to `tx` as it arrives.
+pub fn read_multiplexed<R>(mut reader: R, tx: Sender<(Stream, Vec<u8>)>)
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        loop {
+            let (stream, payload) = match read_frame(&mut reader) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("failed to read exec output: {}", err);
+                    break;
+                }
+            };
+
+            if tx.send((stream, payload)).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn read_chunked(body: &[u8], buf_size: usize) -> io::Result<Vec<u8>> {
+        let mut chunked = Chunked::new(BufReader::new(body));
+        let mut data = Vec::new();
+        let mut buf = vec![0; buf_size];
+        loop {
+            match chunked.read(&mut buf)? {
+                0 => return Ok(data),
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let body = b"4\r\nWiki\r\n5;name=value\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\n\r\n";
+        for &buf_size in &[1, 3, 4096] {
+            assert_eq!(read_chunked(body, buf_size).unwrap(), b"Wikipedia in\r\n\r\nchunks.");
+        }
+        assert_eq!(read_chunked(b"0\r\n\r\n", 16).unwrap(), b"");
+    }
+
+    #[test]
+    fn rejects_broken_chunked_bodies() {
+        let err = read_chunked(b"a\r\nshort", 16).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = read_chunked(b"zz\r\ndata\r\n", 16).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn frame(stream: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![stream, 0, 0, 0];
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn demultiplexes_exec_output() {
+        let mut output = frame(1, b"out ");
+        output.extend(frame(2, b"err"));
+        output.extend(frame(1, b""));
+        output.extend(frame(1, b"put"));
+
+        let mut reader = &output[..];
+        match read_frame(&mut reader).unwrap() {
+            Some((Stream::Stdout, payload)) => assert_eq!(payload, b"out "),
+            _ => panic!("expected stdout"),
+        }
+        match read_frame(&mut reader).unwrap() {
+            Some((Stream::Stderr, payload)) => assert_eq!(payload, b"err"),
+            _ => panic!("expected stderr"),
+        }
+
+        let (stdout, stderr) = demultiplex(&output[..]).unwrap();
+        assert_eq!(stdout, b"out put");
+        assert_eq!(stderr, b"err");
+        assert!(read_frame(&mut &b""[..]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        let output = frame(1, b"output");
+        let err = demultiplex(&output[..output.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn encodes_registry_credentials() {
+        let config: CliConfig = serde_json::from_value(serde_json::json!({
+            "auths": {
+                "registry.example.com": { "auth": BASE64.encode("user:pass:word") },
+                "broken.example.com": { "auth": "not base64!" },
+                "empty.example.com": {},
+            },
+            "credHelpers": { "helped.example.com": "bashable-notes-missing-helper" },
+        }))
+        .unwrap();
+
+        let header = BASE64_URL.decode(encode_auths(&config)).unwrap();
+        let auths: Value = serde_json::from_slice(&header).unwrap();
+        assert_eq!(
+            auths,
+            serde_json::json!({
+                "registry.example.com": {
+                    "username": "user",
+                    "password": "pass:word",
+                    "serveraddress": "registry.example.com",
+                },
+            })
+        );
+    }
+}
@@ -0,0 +1,342 @@
+use std::io::{self, Read, Write};
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use artifacts::Artifact;
+use binary::{self, Binary};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ExecStatus {
+    Completed,
+    Cancelled,
+    TimedOut,
+    /// Killed while the container was over its memory limit
+    OutOfMemory,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// Set if the block wrote something to stdout that isn't UTF-8 text
+    pub binary: Option<Binary>,
+    pub status: ExecStatus,
+    /// Exit code of the command, if it exited on its own and the backend
+    /// reports one
+    pub exit_code: Option<i32>,
+    /// Signal the command was killed by
+    pub signal: Option<i32>,
+    pub duration: Duration,
+    /// Set if the output is from an earlier run
+    #[serde(skip)]
+    pub cached: bool,
+    /// Files the block produced, found after it runs
+    #[serde(skip)]
+    pub artifacts: Vec<Artifact>,
+}
+
+impl ExecOutput {
+    /// Whether the block ran to completion without failing.
+    pub fn succeeded(&self) -> bool {
+        self.status == ExecStatus::Completed
+            && self.signal.is_none()
+            && self.exit_code.is_none_or(|code| code == 0)
+    }
+}
+
+/// Splits the exit code of a shell into the command's exit code or the
+/// signal that killed it, which shells report as 128 plus the signal.
+///
+/// Only for backends that just get an exit code, like docker's exec, as a
+/// command that exits with 130 itself can't be told apart from one killed
+/// by signal 2.
+pub fn split_exit_code(code: i32) -> (Option<i32>, Option<i32>) {
+    match code {
+        129..=192 => (None, Some(code - 128)),
+        _ => (Some(code), None),
+    }
+}
+
+/// A long running process started with `Executor::spawn`.
+pub struct Process {
+    pub stdin: Box<dyn Write + Send>,
+    pub output: Receiver<(Stream, Vec<u8>)>,
+    /// Kills the process along with anything it spawned
+    pub kill: Box<dyn Fn() -> io::Result<()> + Send>,
+}
+
+/// An interactive shell started with `Executor::terminal`, attached to a
+/// pseudo terminal so programs behave as they would in a real one.
+pub struct Terminal {
+    /// The shell, its output includes the terminal's escape sequences
+    pub process: Process,
+    /// Changes the size of the terminal, in rows and columns
+    pub resize: Box<dyn Fn(u16, u16) -> io::Result<()> + Send>,
+}
+
+/// A code block to run.
+pub struct ExecRequest<'a> {
+    pub id: &'a str,
+    pub cmd: &'a str,
+    pub code: &'a str,
+    /// Extra enviroment variables to run the block with
+    pub env: &'a [(String, String)],
+    pub timeout: Option<Duration>,
+    /// Set for interactive blocks, whose stdin is kept open for input from
+    /// the client instead of being sent the code
+    pub input: Option<Input<'a>>,
+}
+
+/// Lets the client type into a running block's stdin.
+pub struct Input<'a> {
+    pub data: &'a Receiver<String>,
+    /// Called when the block looks like it is waiting for input
+    pub on_waiting: &'a dyn Fn(),
+}
+
+/// Largest block whose code is also put in `$CODE`. Linux limits a single
+/// enviroment variable to 128KB, past which the block would fail to start.
+const MAX_CODE_ENV: usize = 64 * 1024;
+
+/// Returns the code of a block to set `$CODE` to, or `None` if it is too
+/// big for the enviroment, in which case it is only sent through stdin and
+/// `$CODE_FILE`.
+pub fn code_env<'a>(request: &ExecRequest<'a>) -> Option<&'a str> {
+    if request.code.len() > MAX_CODE_ENV {
+        warn!(
+            "not setting $CODE for block {}, its code is over {}KB",
+            request.id,
+            MAX_CODE_ENV / 1024
+        );
+        return None;
+    }
+    Some(request.code)
+}
+
+/// Names an exec of block `id` uniquely, as renders of a notebook share its
+/// environment and so can be running the same block at once.
+pub fn exec_name(id: &str) -> String {
+    static EXECS: AtomicUsize = AtomicUsize::new(0);
+    format!("{}-{}", id, EXECS.fetch_add(1, Ordering::Relaxed))
+}
+
+/// How long an interactive block has to be quiet after writing a prompt
+/// before it is taken to be waiting for input.
+const WAITING_AFTER: Duration = Duration::from_millis(500);
+
+/// Backend that notebooks are executed by.
+///
+/// `build` and `start` are called once before the first block runs, `kill`
+/// once after the last. Everything takes `&self` so a running executor can be
+/// paused or killed from another thread.
+pub trait Executor: Send + Sync {
+    /// Prepares the environment, e.g. building the notebook's image, passing
+    /// any build output to `on_log`.
+    fn build(&self, on_log: &mut dyn FnMut(String)) -> io::Result<()>;
+
+    /// Starts the environment blocks are executed in.
+    fn start(&self) -> io::Result<()>;
+
+    /// Runs a block, streaming its output to `on_output`. The block is killed
+    /// once `cancelled` returns true or its timeout passes.
+    fn exec(
+        &self,
+        request: &ExecRequest,
+        cancelled: &dyn Fn() -> bool,
+        on_output: &mut dyn FnMut(Stream, String),
+    ) -> io::Result<ExecOutput>;
+
+    /// Starts `cmd` with bash as a long running process that is written to
+    /// through its stdin, with `env` set in its environment.
+    fn spawn(&self, id: &str, cmd: &str, env: &[(&str, &str)]) -> io::Result<Process>;
+
+    /// Starts an interactive shell in a terminal of `rows` by `cols`, for
+    /// poking around the environment blocks run in.
+    fn terminal(&self, rows: u16, cols: u16) -> io::Result<Terminal>;
+
+    fn pause(&self) -> io::Result<()>;
+
+    fn resume(&self) -> io::Result<()>;
+
+    /// Whether the environment is paused, during which block timeouts don't
+    /// count down.
+    fn is_paused(&self) -> bool;
+
+    /// Stops the environment along with anything still running in it.
+    fn kill(&self) -> io::Result<()>;
+
+    /// Whether the environment can still run blocks, false once it has been
+    /// killed.
+    fn is_running(&self) -> bool;
+}
+
+pub fn not_running() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "notebook is not running")
+}
+
+/// Receives the next piece of a block's output, waiting at most the given
+/// duration. Reports `Disconnected` once the block has finished.
+pub type NextOutput<'a> = dyn FnMut(Duration) -> Result<(Stream, Vec<u8>), RecvTimeoutError> + 'a;
+
+/// Forwards a running block's output from `next` to `on_output` until it is
+/// disconnected, calling `kill` and returning early if the block is cancelled
+/// or times out. Time spent while `paused` returns true doesn't count towards
+/// the timeout. Output that isn't text isn't forwarded, it is only returned
+/// once the block finishes. Input for interactive blocks is written to
+/// `stdin` and echoed to stdout.
+pub fn run_streaming(
+    next: &mut NextOutput,
+    request: &ExecRequest,
+    cancelled: &dyn Fn() -> bool,
+    on_output: &mut dyn FnMut(Stream, String),
+    kill: &dyn Fn() -> io::Result<()>,
+    paused: &dyn Fn() -> bool,
+    mut stdin: Option<&mut dyn Write>,
+) -> ExecOutput {
+    let started = Instant::now();
+    let mut deadline = request.timeout.map(|timeout| started + timeout);
+    let mut checked = started;
+
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    // bytes of a character split between reads, and whether the stream is binary
+    let mut pending = [Vec::new(), Vec::new()];
+    let mut binary = [false, false];
+    let mut status = ExecStatus::Completed;
+    // interactive blocks are waiting for input if they go quiet after
+    // printing something that isn't a whole line
+    let mut last_active = started;
+    let mut prompted = true;
+    let mut waiting = false;
+    loop {
+        match next(Duration::from_millis(100)) {
+            Ok((_, ref data)) if data.is_empty() => {}
+            Ok((stream, data)) => {
+                let (output, i) = match stream {
+                    Stream::Stdout => (&mut stdout, 0),
+                    Stream::Stderr => (&mut stderr, 1),
+                };
+                output.extend_from_slice(&data);
+                binary[i] = binary[i] || binary::is_binary(output, &data);
+
+                if !binary[i] {
+                    pending[i].extend(data);
+                    let text = decode_utf8(&mut pending[i]);
+                    if !text.is_empty() {
+                        prompted = !text.ends_with('\n');
+                        on_output(stream, text);
+                    }
+                }
+                last_active = Instant::now();
+                waiting = false;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if let (Some(input), Some(stdin)) = (request.input.as_ref(), stdin.as_mut()) {
+            while let Ok(data) = input.data.try_recv() {
+                if let Err(err) = stdin.write_all(data.as_bytes()).and_then(|_| stdin.flush()) {
+                    warn!("unable to write input to block {}: {}", request.id, err);
+                }
+                // echoed like a terminal would, so the output reads as it was typed
+                if !binary[0] {
+                    stdout.extend_from_slice(data.as_bytes());
+                    prompted = !data.ends_with('\n');
+                    on_output(Stream::Stdout, data);
+                }
+                last_active = Instant::now();
+                waiting = false;
+            }
+
+            if !waiting && prompted && last_active.elapsed() >= WAITING_AFTER {
+                debug!("block {} is waiting for input", request.id);
+                (input.on_waiting)();
+                waiting = true;
+            }
+        }
+
+        let now = Instant::now();
+        if paused() {
+            deadline = deadline.map(|deadline| deadline + (now - checked));
+        }
+        checked = now;
+
+        if cancelled() {
+            info!("cancelling block {}", request.id);
+            status = ExecStatus::Cancelled;
+        } else if deadline.is_some_and(|deadline| now >= deadline) {
+            info!("block {} timed out", request.id);
+            status = ExecStatus::TimedOut;
+        } else {
+            continue;
+        }
+
+        if let Err(err) = kill() {
+            warn!("failed to kill block {}: {}", request.id, err);
+        }
+        // don't wait on anything that escaped the kill and kept the output open
+        break;
+    }
+
+    let (stdout, binary) = binary::decode(stdout);
+    let stderr = String::from_utf8_lossy(&stderr).into_owned();
+    debug!("block {} output: {} {}", request.id, stdout, stderr);
+
+    ExecOutput {
+        stdout,
+        stderr,
+        binary,
+        status,
+        exit_code: None,
+        signal: None,
+        duration: started.elapsed(),
+        cached: false,
+        artifacts: Vec::new(),
+    }
+}
+
+/// Takes the longest prefix of `pending` that doesn't end part way through a
+/// multi-byte character, leaving the incomplete character in `pending`.
+pub fn decode_utf8(pending: &mut Vec<u8>) -> String {
+    let complete = match str::from_utf8(pending) {
+        Err(ref err) if err.error_len().is_none() => err.valid_up_to(),
+        _ => pending.len(),
+    };
+    let rest = pending.split_off(complete);
+    let data = String::from_utf8_lossy(pending).into_owned();
+    *pending = rest;
+    data
+}
+
+/// Forwards everything read from `reader` to `tx` as it arrives.
+pub fn read_stream<R>(stream: Stream, mut reader: R, tx: Sender<(Stream, Vec<u8>)>)
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut buf = [0; 4096];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    warn!("failed to read {:?}: {}", stream, err);
+                    break;
+                }
+            };
+            if tx.send((stream, buf[..n].to_vec())).is_err() {
+                return;
+            }
+        }
+    });
+}
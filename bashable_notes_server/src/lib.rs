@@ -10,19 +10,45 @@ extern crate tempdir;
 extern crate time;
 extern crate ws;
 extern crate notify;
+extern crate tar;
+extern crate sha2;
+extern crate glob;
+extern crate signal_hook;
+extern crate mime_guess;
+extern crate base64;
 
 mod server;
 mod renderer;
+mod executor;
+mod engine;
+mod context;
 mod docker;
+mod local;
+mod session;
+mod manager;
+mod cache;
+mod artifacts;
+mod ansi;
+mod binary;
 
 use server::{Server, AppMessage};
+use renderer::Execution;
+use manager::Manager;
 
 use notify::{RecommendedWatcher, Watcher, RecursiveMode};
 use notify::DebouncedEvent;
 use std::sync::mpsc::channel;
 use std::time::Duration;
 use std::env;
+use std::process;
 use std::thread;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+/// Where `bashable_notes` serves the UI
+const DEFAULT_ORIGINS: &str = "http://127.0.0.1:3000,http://localhost:3000";
 
 fn watch(broadcaster: ws::Sender) -> notify::Result<()> {
     let (tx, rx) = channel();
@@ -49,10 +75,52 @@ fn watch(broadcaster: ws::Sender) -> notify::Result<()> {
     }
 }
 
+/// Stops every notebook container once the server is asked to exit.
+fn stop_on_signal(manager: Arc<Manager>) -> std::io::Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("received signal {}, stopping notebooks", signal);
+            manager.stop_all();
+            process::exit(128 + signal);
+        }
+    });
+    Ok(())
+}
+
+/// Origins of the pages allowed to connect, the UI served by `bashable_notes`
+/// unless `ALLOWED_ORIGINS` lists others.
+fn allowed_origins() -> Arc<Vec<String>> {
+    let origins = env::var("ALLOWED_ORIGINS").unwrap_or_else(|_| String::from(DEFAULT_ORIGINS));
+    info!("enviroment variable ALLOWED_ORIGINS = {}", origins);
+    Arc::new(
+        origins
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect(),
+    )
+}
+
 pub fn start(address: &str) {
     info!("Starting websocket on ws://{}", address);
-    
-    let socket = ws::WebSocket::new(|out| Server { out: out }).unwrap();
+
+    if env::var("EXECUTOR").unwrap_or_default() != "local" {
+        if let Err(err) = docker::remove_stale_containers(&engine::Engine::new()) {
+            warn!("unable to remove stale containers: {}", err);
+        }
+    }
+
+    let origins = allowed_origins();
+    let manager = Manager::new();
+    stop_on_signal(manager.clone()).unwrap();
+    let socket = ws::WebSocket::new(move |out| Server {
+        out,
+        execution: Execution::default(),
+        manager: manager.clone(),
+        terminals: Arc::new(Mutex::new(HashMap::new())),
+        origins: origins.clone(),
+    }).unwrap();
 
     let broadcaster = socket.broadcaster();
     let watch_handle = thread::spawn(move || {
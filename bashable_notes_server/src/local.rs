@@ -0,0 +1,314 @@
+use std::process::{Command, Stdio};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use tempdir::TempDir;
+use executor::{self, ExecOutput, ExecRequest, Executor, Process, Stream, Terminal};
+
+/// Runs notebooks as plain subprocesses in the notebook directory, for
+/// machines without docker.
+///
+/// There is no isolation: blocks run as the server's user with full access to
+/// the host, so only use it with trusted notebooks.
+pub struct LocalExecutor {
+    notebook_dir: PathBuf,
+    /// Process groups of the blocks and processes currently running, by
+    /// `exec_name`, as runs sharing the executor can run the same block
+    running: Arc<Mutex<HashMap<String, u32>>>,
+    paused: AtomicBool,
+}
+
+impl LocalExecutor {
+    pub fn new(notebook_dir: &Path) -> Self {
+        LocalExecutor {
+            notebook_dir: notebook_dir.to_path_buf(),
+            running: Arc::new(Mutex::new(HashMap::new())),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    /// Sends `signal` to every running block's process group.
+    fn signal_all(&self, signal: &str) -> io::Result<()> {
+        for pgid in self.running.lock().unwrap().values() {
+            signal_group(*pgid, signal)?;
+        }
+        Ok(())
+    }
+}
+
+fn signal_group(pgid: u32, signal: &str) -> io::Result<()> {
+    let output = Command::new("kill")
+        .arg(format!("-{}", signal))
+        .arg("--")
+        .arg(format!("-{}", pgid))
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(stderr.trim().to_string()));
+    }
+    Ok(())
+}
+
+impl Executor for LocalExecutor {
+    fn build(&self, _on_log: &mut dyn FnMut(String)) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn start(&self) -> io::Result<()> {
+        info!("running notebook locally in {:?}", self.notebook_dir);
+        Ok(())
+    }
+
+    fn exec(
+        &self,
+        request: &ExecRequest,
+        cancelled: &dyn Fn() -> bool,
+        on_output: &mut dyn FnMut(Stream, String),
+    ) -> io::Result<ExecOutput> {
+        let code_dir = TempDir::new("bashable_notes_code")?;
+        let code_file = code_dir.path().join(request.id);
+        fs::write(&code_file, request.code)?;
+
+        let mut command = Command::new("bash");
+        let command = command
+            .arg("-c")
+            .arg(request.cmd)
+            .current_dir(&self.notebook_dir)
+            .envs(request.env.iter().cloned())
+            .env("CODE_FILE", &code_file)
+            .process_group(0) // so the block and its children can be signalled together
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(code) = executor::code_env(request) {
+            command.env("CODE", code);
+        }
+
+        debug!("local exec command: {:?}", command);
+        let mut child = command.spawn()?;
+        let pgid = child.id();
+        let name = executor::exec_name(request.id);
+        self.running.lock().unwrap().insert(name.clone(), pgid);
+
+        let mut stdin = child.stdin.take().unwrap();
+        let mut input = None;
+        if request.input.is_some() {
+            input = Some(stdin);
+        } else {
+            let code = request.code.to_string();
+            thread::spawn(move || {
+                // commands that don't read their stdin close it early
+                if let Err(err) = stdin.write_all(code.as_bytes()) {
+                    debug!("unable to write code to stdin: {}", err);
+                }
+            });
+        }
+
+        let (tx, rx) = channel();
+        executor::read_stream(Stream::Stdout, child.stdout.take().unwrap(), tx.clone());
+        executor::read_stream(Stream::Stderr, child.stderr.take().unwrap(), tx);
+
+        let mut output = executor::run_streaming(
+            &mut |timeout| rx.recv_timeout(timeout),
+            request,
+            cancelled,
+            on_output,
+            &|| signal_group(pgid, "KILL"),
+            &|| self.is_paused(),
+            input.as_mut().map(|stdin| stdin as &mut dyn Write),
+        );
+
+        self.running.lock().unwrap().remove(&name);
+        let status = child.wait()?;
+        debug!("block {} exited with {}", request.id, status);
+        output.exit_code = status.code();
+        output.signal = status.signal();
+        Ok(output)
+    }
+
+    fn spawn(&self, id: &str, cmd: &str, env: &[(&str, &str)]) -> io::Result<Process> {
+        let mut command = Command::new("bash");
+        let command = command
+            .arg("-c")
+            .arg(cmd)
+            .current_dir(&self.notebook_dir)
+            .envs(env.iter().cloned())
+            .process_group(0)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        debug!("local spawn command: {:?}", command);
+        let mut child = command.spawn()?;
+        let pgid = child.id();
+        let name = executor::exec_name(id);
+        self.running.lock().unwrap().insert(name.clone(), pgid);
+
+        let (tx, rx) = channel();
+        executor::read_stream(Stream::Stdout, child.stdout.take().unwrap(), tx.clone());
+        executor::read_stream(Stream::Stderr, child.stderr.take().unwrap(), tx);
+        let stdin = child.stdin.take().unwrap();
+
+        // reap the process whenever it exits
+        let running = self.running.clone();
+        thread::spawn(move || {
+            if let Err(err) = child.wait() {
+                warn!("failed to wait for {}: {}", name, err);
+            }
+            running.lock().unwrap().remove(&name);
+        });
+
+        Ok(Process {
+            stdin: Box::new(stdin),
+            output: rx,
+            kill: Box::new(move || signal_group(pgid, "KILL")),
+        })
+    }
+
+    fn terminal(&self, _rows: u16, _cols: u16) -> io::Result<Terminal> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "terminals need the docker executor, use your own shell instead",
+        ))
+    }
+
+    fn pause(&self) -> io::Result<()> {
+        self.signal_all("STOP")?;
+        self.paused.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn resume(&self) -> io::Result<()> {
+        self.signal_all("CONT")?;
+        self.paused.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn kill(&self) -> io::Result<()> {
+        self.signal_all("KILL")
+    }
+
+    fn is_running(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use executor::ExecStatus;
+
+    /// Runs `cmd` as a block, returning its output and the chunks of stdout
+    /// it was streamed in.
+    fn run(
+        cmd: &str,
+        timeout: Option<Duration>,
+        cancelled: &dyn Fn() -> bool,
+    ) -> (ExecOutput, Vec<String>) {
+        let dir = TempDir::new("bashable_notes_test").unwrap();
+        let executor = LocalExecutor::new(dir.path());
+        let request = ExecRequest {
+            id: "block-0",
+            cmd,
+            code: "",
+            env: &[],
+            timeout,
+            input: None,
+        };
+        let mut chunks = Vec::new();
+        let output = executor
+            .exec(&request, cancelled, &mut |stream, data| {
+                if let Stream::Stdout = stream {
+                    chunks.push(data);
+                }
+            })
+            .unwrap();
+        assert!(executor.running.lock().unwrap().is_empty());
+        (output, chunks)
+    }
+
+    #[test]
+    fn streams_output() {
+        let (output, chunks) = run("echo a; sleep 0.3; echo b; echo c >&2", None, &|| false);
+        assert_eq!(chunks, vec!["a\n", "b\n"]);
+        assert_eq!(output.stdout, "a\nb\n");
+        assert_eq!(output.stderr, "c\n");
+        assert_eq!(output.status, ExecStatus::Completed);
+        assert_eq!(output.exit_code, Some(0));
+        assert!(output.succeeded());
+    }
+
+    #[test]
+    fn reports_exit_code() {
+        let (output, _) = run("echo failing; exit 3", None, &|| false);
+        assert_eq!(output.exit_code, Some(3));
+        assert_eq!(output.signal, None);
+        assert!(!output.succeeded());
+    }
+
+    #[test]
+    fn keeps_exit_codes_past_128() {
+        let (output, _) = run("exit 130", None, &|| false);
+        assert_eq!(output.exit_code, Some(130));
+        assert_eq!(output.signal, None);
+
+        let (output, _) = run("kill -INT $$", None, &|| false);
+        assert_eq!(output.exit_code, None);
+        assert_eq!(output.signal, Some(2));
+    }
+
+    #[test]
+    fn kills_blocks_that_time_out() {
+        let started = Instant::now();
+        let (output, _) = run("echo started; sleep 10", Some(Duration::from_millis(300)), &|| false);
+        assert_eq!(output.status, ExecStatus::TimedOut);
+        assert_eq!(output.stdout, "started\n");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn tracks_each_run_of_a_block() {
+        let dir = TempDir::new("bashable_notes_test").unwrap();
+        let executor = LocalExecutor::new(dir.path());
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    let request = ExecRequest {
+                        id: "block-0",
+                        cmd: "sleep 0.5",
+                        code: "",
+                        env: &[],
+                        timeout: None,
+                        input: None,
+                    };
+                    executor.exec(&request, &|| false, &mut |_, _| {}).unwrap()
+                });
+            }
+            thread::sleep(Duration::from_millis(200));
+            assert_eq!(executor.running.lock().unwrap().len(), 2);
+        });
+        assert!(executor.running.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn kills_cancelled_blocks() {
+        let started = Instant::now();
+        let cancelled = || started.elapsed() > Duration::from_millis(300);
+        let (output, _) = run("sleep 10 & sleep 10", None, &cancelled);
+        assert_eq!(output.status, ExecStatus::Cancelled);
+        assert_eq!(output.signal, Some(9));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use executor::Executor;
+use session::Sessions;
+
+/// Seconds an environment is kept running after it was last used, unless
+/// IDLE_TIMEOUT says otherwise.
+const DEFAULT_IDLE_TIMEOUT: u64 = 15 * 60;
+
+/// A started environment and the sessions running in it.
+pub struct Environment {
+    pub executor: Arc<dyn Executor>,
+    pub sessions: Sessions,
+}
+
+impl Environment {
+    fn stop(&self) {
+        self.sessions.clear();
+        if let Err(err) = self.executor.kill() {
+            warn!("failed to stop environment: {}", err);
+        }
+    }
+}
+
+struct Entry {
+    environment: Arc<Environment>,
+    /// Identifies the configuration the environment was started with
+    fingerprint: String,
+    last_used: Instant,
+}
+
+/// Keeps notebook environments running between runs, so re-rendering a
+/// notebook after a save doesn't start its containers again. Environments
+/// are stopped once they have been idle for the idle timeout.
+pub struct Manager {
+    /// Running environments, by notebook path and environment name
+    environments: Mutex<HashMap<(PathBuf, String), Entry>>,
+    idle_timeout: Duration,
+}
+
+impl Manager {
+    pub fn new() -> Arc<Self> {
+        let idle_timeout = env::var("IDLE_TIMEOUT")
+            .ok()
+            .and_then(|timeout| timeout.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+        info!("enviroment variable IDLE_TIMEOUT = {}", idle_timeout);
+
+        let manager = Arc::new(Manager {
+            environments: Mutex::new(HashMap::new()),
+            idle_timeout: Duration::from_secs(idle_timeout),
+        });
+
+        let reaper = manager.clone();
+        let interval = reaper.idle_timeout.min(Duration::from_secs(30));
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            reaper.reap();
+        });
+
+        manager
+    }
+
+    /// Returns a notebook's running environment `name`. If it was started
+    /// with a configuration other than `fingerprint`, or has been killed, it
+    /// is stopped instead.
+    pub fn get(&self, notebook: &Path, name: &str, fingerprint: &str) -> Option<Arc<Environment>> {
+        let key = (notebook.to_path_buf(), name.to_string());
+        let mut environments = self.environments.lock().unwrap();
+        let mut entry = environments.remove(&key)?;
+
+        if entry.fingerprint == fingerprint && entry.environment.executor.is_running() {
+            entry.last_used = Instant::now();
+            let environment = entry.environment.clone();
+            environments.insert(key, entry);
+            return Some(environment);
+        }
+        drop(environments);
+
+        info!("environment {} of {:?} changed or was killed, stopping it", name, notebook);
+        entry.environment.stop();
+        None
+    }
+
+    /// Keeps a newly started environment running for later runs.
+    pub fn insert(&self, notebook: &Path, name: &str, fingerprint: String, environment: Arc<Environment>) {
+        let entry = Entry {
+            environment,
+            fingerprint,
+            last_used: Instant::now(),
+        };
+        let key = (notebook.to_path_buf(), name.to_string());
+        let replaced = self.environments.lock().unwrap().insert(key, entry);
+
+        if let Some(replaced) = replaced {
+            replaced.environment.stop();
+        }
+    }
+
+    /// Marks an environment as used, restarting its idle timeout.
+    pub fn touch(&self, notebook: &Path, name: &str) {
+        let key = (notebook.to_path_buf(), name.to_string());
+        if let Some(entry) = self.environments.lock().unwrap().get_mut(&key) {
+            entry.last_used = Instant::now();
+        }
+    }
+
+    /// Stops a notebook's environment `name`, or all of them, so the next
+    /// run starts from a fresh container.
+    pub fn restart(&self, notebook: &Path, name: Option<&str>) {
+        let stopped = self.remove(|(path, environment), _| {
+            path == notebook && name.is_none_or(|name| name == environment)
+        });
+        for ((_, name), entry) in stopped {
+            info!("restarting environment {} of {:?}", name, notebook);
+            entry.environment.stop();
+        }
+    }
+
+    /// Stops every environment, e.g. before the server exits.
+    pub fn stop_all(&self) {
+        for ((notebook, name), entry) in self.remove(|_, _| true) {
+            info!("stopping environment {} of {:?}", name, notebook);
+            entry.environment.stop();
+        }
+    }
+
+    /// Stops environments that no run is using and that have been idle for
+    /// longer than the idle timeout.
+    fn reap(&self) {
+        let idle_timeout = self.idle_timeout;
+        let idle = self.remove(|_, entry| {
+            Arc::strong_count(&entry.environment) == 1 && entry.last_used.elapsed() >= idle_timeout
+        });
+        for ((notebook, name), entry) in idle {
+            info!("stopping idle environment {} of {:?}", name, notebook);
+            entry.environment.stop();
+        }
+    }
+
+    /// Removes the environments matching `predicate`, returning them so they
+    /// can be stopped without holding the lock.
+    fn remove<P>(&self, predicate: P) -> Vec<((PathBuf, String), Entry)>
+    where
+        P: Fn(&(PathBuf, String), &Entry) -> bool,
+    {
+        let mut environments = self.environments.lock().unwrap();
+        let keys: Vec<_> = environments
+            .iter()
+            .filter(|&(key, entry)| predicate(key, entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        keys.into_iter()
+            .filter_map(|key| environments.remove(&key).map(|entry| (key, entry)))
+            .collect()
+    }
+}
@@ -1,5 +1,12 @@
 use pulldown_cmark::{html, Event, Options, Parser, Tag};
-use docker;
+use ansi::{self, HtmlChunk};
+use artifacts;
+use cache::Cache;
+use docker::{self, DockerExecutor};
+use executor::{self, ExecOutput, ExecRequest, ExecStatus, Executor, Input, Stream, Terminal};
+use local::LocalExecutor;
+use manager::{Environment, Manager};
+use session::Sessions;
 use std::path::Path;
 use std::borrow::Cow;
 use std::fs::File;
@@ -7,13 +14,164 @@ use std::fs;
 use std::io::{Read, Write};
 use std::env;
 use std::ffi::OsString;
+use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::io;
+use std::time::Duration;
 use serde_json;
+use sha2::{Digest, Sha256};
 
 pub struct Renderer {
+    manager: Arc<Manager>,
     notebook_dir: PathBuf,
-    container: Option<docker::Container>,
-    blocks: Vec<CodeBlock>,
+    notebook_path: PathBuf,
+    /// Rebuild images even if they are up to date
+    rebuild: bool,
+    /// Environments that have been started, by name, each with the error it
+    /// failed to start with. An environment's entry is locked while it
+    /// starts, so only blocks that need it wait for it to finish.
+    starting: Mutex<HashMap<String, Arc<Mutex<Option<String>>>>>,
+    backend: Backend,
+    default_network: docker::Network,
+    execution: Execution,
+    options: NotebookOptions,
+    /// Blocks left to run, in the order they run in
+    blocks: VecDeque<CodeBlock>,
+    cache: Option<Cache>,
+}
+
+/// Where notebooks are executed, chosen with the EXECUTOR enviroment variable.
+enum Backend {
+    Docker,
+    Local,
+}
+
+/// Environment blocks run in unless they choose another one.
+const DEFAULT_ENVIRONMENT: &str = "default";
+
+/// Handle used to control a notebook's execution from another thread.
+#[derive(Clone, Default)]
+pub struct Execution {
+    /// Environments used by the run so far, by name
+    environments: Arc<Mutex<HashMap<String, Arc<Environment>>>>,
+    cancelled: Arc<Mutex<HashSet<String>>>,
+    /// Set once every block of the run is cancelled
+    stopped: Arc<AtomicBool>,
+    /// Stdin of the interactive blocks that are running, by id
+    inputs: Arc<Mutex<HashMap<String, Sender<String>>>>,
+}
+
+impl Execution {
+    fn environment(&self, name: &str) -> Option<Arc<Environment>> {
+        self.environments.lock().unwrap().get(name).cloned()
+    }
+
+    fn executors(&self) -> io::Result<Vec<Arc<dyn Executor>>> {
+        let environments = self.environments.lock().unwrap();
+        if environments.is_empty() {
+            return Err(executor::not_running());
+        }
+        Ok(environments
+            .values()
+            .map(|environment| environment.executor.clone())
+            .collect())
+    }
+
+    /// Stops block `id`, killing it if it is already running.
+    pub fn cancel(&self, id: &str) {
+        info!("cancelling block {}", id);
+        self.cancelled.lock().unwrap().insert(id.to_string());
+    }
+
+    /// Cancels every block of the run, e.g. because a newer run of the
+    /// notebook has replaced it. A paused run is resumed first, as paused
+    /// blocks can't be killed and the next run needs the environments.
+    pub fn stop(&self) {
+        info!("stopping run");
+        self.resume_paused();
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Resumes any environment of the run that is still paused.
+    fn resume_paused(&self) {
+        for environment in self.environments.lock().unwrap().values() {
+            if environment.executor.is_paused() {
+                if let Err(err) = environment.executor.resume() {
+                    warn!("unable to resume environment: {}", err);
+                }
+            }
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    fn is_cancelled(&self, id: &str) -> bool {
+        self.is_stopped() || self.cancelled.lock().unwrap().contains(id)
+    }
+
+    /// Writes `data` to the stdin of block `id`.
+    pub fn send_input(&self, id: &str, data: String) -> io::Result<()> {
+        let inputs = self.inputs.lock().unwrap();
+        let sender = inputs.get(id).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("block {} isn't reading input", id))
+        })?;
+        sender
+            .send(data)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, format!("block {} has finished", id)))
+    }
+
+    pub fn pause(&self) -> io::Result<()> {
+        for executor in self.executors()? {
+            executor.pause()?;
+        }
+        Ok(())
+    }
+
+    pub fn resume(&self) -> io::Result<()> {
+        for executor in self.executors()? {
+            executor.resume()?;
+        }
+        Ok(())
+    }
+}
+
+/// Notebook wide options, written as json front matter between `---` lines
+/// at the top of the markdown file.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct NotebookOptions {
+    timeout: Option<u64>,
+    network: Option<docker::Network>,
+    #[serde(flatten)]
+    limits: docker::Limits,
+    /// Extra environments blocks can run in, by name
+    #[serde(default)]
+    environments: HashMap<String, EnvironmentOptions>,
+    /// Enviroment variables set for every block
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// Maximum number of blocks run at the same time, the number of cpus by
+    /// default
+    concurrency: Option<usize>,
+    /// User blocks run as in the docker container, e.g. `"root"` or
+    /// `"1000:1000"`, the owner of the notebook directory by default
+    user: Option<String>,
+}
+
+/// An environment declared in the notebook options, either built from a
+/// Dockerfile or using an existing image.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct EnvironmentOptions {
+    /// Path of the Dockerfile, relative to the notebook directory
+    dockerfile: Option<String>,
+    image: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -21,6 +179,32 @@ pub struct CodeBlockOptions {
     hide: Option<bool>,
     name: Option<String>,
     cmd: Option<String>,
+    /// Language of the long running session to run the block in
+    session: Option<String>,
+    /// Name of the environment to run the block in
+    environment: Option<String>,
+    /// Existing docker image to run the block in
+    image: Option<String>,
+    timeout: Option<u64>,
+    /// Enviroment variables set for the block, on top of the notebook's
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// Ids or names of the blocks that have to run before this one
+    #[serde(default)]
+    depends: Vec<String>,
+    /// Run alongside the blocks around it instead of after them
+    parallel: Option<bool>,
+    /// Set to false to always run the block, instead of reusing its output
+    /// when nothing it depends on has changed
+    cache: Option<bool>,
+    /// Glob patterns, relative to /home, of the files the block produces
+    #[serde(default)]
+    artifacts: Vec<String>,
+    /// Set to false to strip colors from the block's output
+    colors: Option<bool>,
+    /// Keep stdin open for input from the client, instead of writing the
+    /// code to it
+    interactive: Option<bool>,
 }
 
 #[derive(Debug, Clone)]
@@ -30,6 +214,14 @@ pub struct CodeBlock {
     start_index: usize,
     end_index: usize,
     code: String,
+    /// Ids of the blocks this block depends on
+    dependencies: Vec<String>,
+    /// Ids of the blocks that have to finish before this one starts
+    after: Vec<String>,
+    /// Why the block can't be run, e.g. it is part of a dependency cycle
+    error: Option<String>,
+    /// Key the block's output is cached under, if it can be cached
+    cache_key: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -45,13 +237,19 @@ pub enum FileTree {
 }
 
 impl CodeBlock {
-    fn new(index: usize) -> Self {
+    /// Creates the `number`th code block of a notebook, counting from 0,
+    /// which starts at event `index`.
+    fn new(number: usize, index: usize) -> Self {
         CodeBlock {
-            id: format!("block-{}", index),
+            id: format!("block-{}", number),
             options: CodeBlockOptions::default(),
             start_index: index,
             end_index: 0,
             code: String::new(),
+            dependencies: Vec::new(),
+            after: Vec::new(),
+            error: None,
+            cache_key: None,
         }
     }
 
@@ -61,22 +259,108 @@ impl CodeBlock {
 }
 
 impl Renderer {
-    pub fn new() -> Self {
+    pub fn new(manager: Arc<Manager>) -> Self {
         let notebook_dir = env::current_dir().unwrap();
         info!("created notebook directory");
 
         let env_exec_cmd = env::var("EXEC_CMD").unwrap_or_default() == "1";
         info!("enviroment variable EXEC_CMD = {}", env_exec_cmd);
 
+        let env_executor = env::var("EXECUTOR").unwrap_or_default();
+        info!("enviroment variable EXECUTOR = {}", env_executor);
+        let backend = match env_executor.as_str() {
+            "local" => Backend::Local,
+            _ => Backend::Docker,
+        };
+
+        // notebooks share the host network unless they, or DEFAULT_NETWORK, say otherwise
+        let default_network = env::var("DEFAULT_NETWORK")
+            .map(docker::Network::from)
+            .unwrap_or(docker::Network::Host);
+        info!("enviroment variable DEFAULT_NETWORK = {:?}", default_network);
+
         Renderer {
-            blocks: Vec::new(),
-            container: None,
+            manager,
+            blocks: VecDeque::new(),
+            cache: Cache::open(),
+            backend,
+            default_network,
+            execution: Execution::default(),
+            options: NotebookOptions::default(),
+            notebook_path: notebook_dir.clone(),
             notebook_dir,
+            rebuild: false,
+            starting: Mutex::new(HashMap::new()),
         }
     }
 
+    /// Makes the notebook's images rebuild from scratch when they are next
+    /// used.
+    pub fn force_rebuild(&mut self) {
+        self.rebuild = true;
+    }
+
+    pub fn execution(&self) -> Execution {
+        self.execution.clone()
+    }
+
+    /// Releases the environments used by the run, leaving them running for
+    /// the next one.
     pub fn clean_up(self) -> () {
-        self.container.map(|c| c.kill());
+        self.execution.resume_paused();
+        for (name, _) in self.execution.environments.lock().unwrap().drain() {
+            self.manager.touch(&self.notebook_path, &name);
+        }
+    }
+
+    /// Stops the environment `name` of the notebook at `path`, or all of
+    /// its environments, so they start from scratch on the next run.
+    pub fn restart_environment(&self, path: &Path, name: Option<&str>) {
+        self.manager.restart(&self.notebook_dir.join(path), name);
+    }
+
+    /// Opens a shell in the environment `name` of the notebook at
+    /// `markdown_path`, or its default one, starting it if it isn't running.
+    /// The environment is kept running for as long as the returned handle
+    /// to it is held.
+    pub fn open_terminal(
+        &mut self,
+        markdown_path: &Path,
+        name: Option<&str>,
+        size: (u16, u16),
+        on_build_log: &dyn Fn(&str, String),
+    ) -> io::Result<(Arc<Environment>, Terminal)> {
+        self.notebook_path = self.notebook_dir.join(markdown_path);
+        let contents = fs::read_to_string(markdown_path)?;
+        self.options = self.parse_front_matter(&contents).0;
+
+        let options = CodeBlockOptions {
+            environment: name.map(String::from),
+            ..CodeBlockOptions::default()
+        };
+        let environment = self.environment(&options, on_build_log)?;
+        let terminal = environment.executor.terminal(size.0, size.1)?;
+        Ok((environment, terminal))
+    }
+
+    /// Splits json front matter from the start of `markdown`, returning the
+    /// notebook options and the remaining markdown.
+    fn parse_front_matter<'a>(&self, markdown: &'a str) -> (NotebookOptions, &'a str) {
+        let front_matter = markdown.strip_prefix("---\n").and_then(|rest| {
+            rest.find("\n---")
+                .map(|end| (&rest[..end], &rest[end + 4..]))
+        });
+
+        match front_matter {
+            Some((json, rest)) => match serde_json::from_str(json) {
+                Ok(options) => (options, rest),
+                Err(err) => {
+                    warn!("unable to parse front matter: {}", err);
+                    (NotebookOptions::default(), markdown)
+                }
+            },
+            None => (NotebookOptions::default(), markdown),
+        }
     }
 
     fn parse<'a>(&self, markdown: &'a str) -> (Vec<CodeBlock>, Vec<Event<'a>>) {
@@ -93,7 +377,7 @@ impl Renderer {
             .map(|(index, event)| {
                 match event {
                     Event::Start(Tag::CodeBlock(_)) => {
-                        let mut block = CodeBlock::new(index);
+                        let block = CodeBlock::new(blocks.len(), index);
                         blocks.push(block);
                         in_block = true;
                         first_line = true;
@@ -104,10 +388,15 @@ impl Renderer {
                             blocks.last_mut().map(|block| {
                                 if first_line {
                                     first_line = false;
+                                    let (line, rest) = match text.find('\n') {
+                                        Some(i) => (&text[..i + 1], &text[i + 1..]),
+                                        None => (&text[..], ""),
+                                    };
                                     let result: serde_json::Result<CodeBlockOptions> =
-                                        serde_json::from_str(text);
+                                        serde_json::from_str(line);
                                     if let Ok(options) = result {
                                         block.options = options;
+                                        block.push_code(rest);
                                     } else {
                                         block.push_code(text);
                                     }
@@ -118,7 +407,7 @@ impl Renderer {
                         }
                     }
 
-                    Event::End(Tag::CodeBlock(_)) => {
+                    Event::End(pulldown_cmark::TagEnd::CodeBlock) => {
                         if in_block {
                             blocks.last_mut().map(|block| block.end_index = index);
                             in_block = false;
@@ -155,10 +444,6 @@ impl Renderer {
         )
     }
 
-    fn internal_error(&self) -> String {
-        String::from("Internal server error")
-    }
-
     pub fn render_file_tree(&self) -> Vec<FileTree> {
         fn recurse_directorys(current_dir: PathBuf) -> Vec<FileTree> {
             let mut tree = Vec::new();
@@ -193,29 +478,35 @@ impl Renderer {
         file_tree
     }
 
-    pub fn render(&mut self, markdown_path: &Path) -> String {
+    pub fn render(&mut self, markdown_path: &Path) -> io::Result<String> {
         info!("rendering started");
+        self.notebook_path = self.notebook_dir.join(markdown_path);
+        if self.rebuild {
+            self.manager.restart(&self.notebook_path, None);
+        }
 
         // read markdown
         info!("reading markdown file");
-        let mut f = File::open(markdown_path).unwrap();
+        let mut f = File::open(markdown_path)?;
         let mut contents = String::new();
-        f.read_to_string(&mut contents).unwrap();
+        f.read_to_string(&mut contents)?;
         info!("markdown file read");
 
         // parse markdown
         info!("parsing markdown");
-        let (blocks, mut events) = self.parse(&contents);
-        self.blocks = blocks.clone();
+        let (options, markdown) = self.parse_front_matter(&contents);
+        self.options = options;
+        let (blocks, mut events) = self.parse(markdown);
+        self.blocks = order_blocks(blocks.clone());
         info!("markdown parsed");
 
         // save files
         for block in blocks.clone() {
             if let Some(ref file_name) = block.options.name {
                 let path = self.notebook_dir.join(file_name);
-                let mut f = File::create(path).unwrap();
-                f.write_all(block.code.as_bytes()).unwrap();
-                f.sync_all().unwrap();
+                let mut f = File::create(path)?;
+                f.write_all(block.code.as_bytes())?;
+                f.sync_all()?;
 
                 info!(r#"saved file "{}""#, file_name);
             }
@@ -225,14 +516,17 @@ impl Renderer {
         let mut insert_offset = 0;
 
         let mut insert_html = |events: &mut Vec<Event>, index: usize, html: String| {
-            events.insert(index + insert_offset, Event::Html(Cow::from(html)));
+            events.insert(index + insert_offset, Event::Html(Cow::from(html).into()));
             insert_offset += 1;
         };
 
         info!("wrapping code blocks");
         for block in blocks {
-            let block_wrapper_begin =
-                format!(r#"<ul uk-accordion="multiple: true" id="{}">"#, block.id);
+            let block_wrapper_begin = format!(
+                r#"<a class="block-cancel uk-float-right" data-block="{0}" uk-icon="icon: close" title="Cancel"></a>
+                <ul uk-accordion="multiple: true" id="{0}">"#,
+                block.id
+            );
             let block_wrapper_end = String::from(r#"</ul>"#);
 
             // begin outer wrapper
@@ -264,69 +558,770 @@ impl Renderer {
         html::push_html(&mut html_buf, events.into_iter());
         info!("html rendered");
 
-        html_buf
+        Ok(html_buf)
     }
 
-    pub fn execution_finished(&self) -> bool {
-        self.blocks.is_empty()
-    }
+    /// Returns the name of the environment a block runs in, along with where
+    /// its image comes from.
+    fn image_source(&self, options: &CodeBlockOptions) -> io::Result<(String, docker::ImageSource)> {
+        if let Some(ref image) = options.image {
+            return Ok((
+                format!("image:{}", image),
+                docker::ImageSource::Existing(image.clone()),
+            ));
+        }
 
-    pub fn execute(&mut self) -> Option<(String, (String, String))> {
-        if self.container.is_none() {
-            // create docker container
-            let docker_file = self.notebook_dir.join("Dockerfile");
-            if !docker_file.as_path().exists() {
-                info!("no Dockerfile, creating default Dockerfile");
+        let name = options
+            .environment
+            .clone()
+            .unwrap_or_else(|| String::from(DEFAULT_ENVIRONMENT));
+        let docker_file = match self.options.environments.get(&name) {
+            Some(&EnvironmentOptions {
+                image: Some(ref image),
+                ..
+            }) => return Ok((name, docker::ImageSource::Existing(image.clone()))),
+            Some(&EnvironmentOptions {
+                dockerfile: Some(ref docker_file),
+                ..
+            }) => self.notebook_dir.join(docker_file),
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("environment {} needs a dockerfile or an image", name),
+                ))
+            }
+            None if name == DEFAULT_ENVIRONMENT => self.notebook_dir.join("Dockerfile"),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("unknown environment {}", name),
+                ))
+            }
+        };
 
-                let mut f = File::create(&docker_file).unwrap();
-                f.write_all(b"FROM ubuntu:latest").unwrap();
-                f.sync_all().unwrap();
+        // images are named after the notebook, so notebooks don't replace
+        // each other's images
+        let image_name = format!(
+            "bashable_notes/{}-{}",
+            image_name_component(&self.notebook_path.to_string_lossy()),
+            image_name_component(&name)
+        );
+        Ok((
+            name,
+            docker::ImageSource::Build {
+                docker_file,
+                name: image_name,
+                rebuild: self.rebuild,
+            },
+        ))
+    }
 
-                info!("created default Dockerfile");
+    fn create_executor(&self, source: docker::ImageSource) -> Arc<dyn Executor> {
+        match self.backend {
+            Backend::Docker => {
+                let network = self
+                    .options
+                    .network
+                    .clone()
+                    .unwrap_or_else(|| self.default_network.clone());
+                Arc::new(DockerExecutor::new(
+                    &self.notebook_dir,
+                    &self.notebook_path,
+                    source,
+                    network,
+                    self.options.limits.clone(),
+                    self.container_user(),
+                ))
             }
+            Backend::Local => Arc::new(LocalExecutor::new(&self.notebook_dir)),
+        }
+    }
 
-            info!("building docker image");
-            let image = match docker::Image::build("notebook-image", &docker_file) {
-                Ok(image) => image,
-                Err(err) => {
-                    error!("error building docker image: {}", err);
-                    let ie = self.internal_error();
-                    return Some((ie.clone(), (ie.clone(), ie.clone())));
-                }
-            };
-            info!("docker image built");
+    /// Returns the user blocks run as in docker containers. Unless the
+    /// notebook chooses one, it is the owner of the notebook directory, so
+    /// the files blocks write there belong to them rather than root.
+    fn container_user(&self) -> String {
+        if let Some(ref user) = self.options.user {
+            return user.clone();
+        }
+        match fs::metadata(&self.notebook_dir) {
+            Ok(metadata) => format!("{}:{}", metadata.uid(), metadata.gid()),
+            Err(err) => {
+                warn!("unable to find the owner of {:?}, running as root: {}", self.notebook_dir, err);
+                String::from("root")
+            }
+        }
+    }
 
-            info!("starting docker container");
-            let container = docker::Container::start(image, &self.notebook_dir);
-            self.container = container.ok();
-            let container = self.container.clone().unwrap();
-            info!("docker container {} started", container.id());
-            // thread::sleep_ms(5000);
+    /// Returns the environment a block runs in, building and starting it
+    /// the first time it is used.
+    ///
+    /// An environment that fails to start isn't retried, so its build output
+    /// is only reported once.
+    fn environment(
+        &self,
+        options: &CodeBlockOptions,
+        on_build_log: &dyn Fn(&str, String),
+    ) -> io::Result<Arc<Environment>> {
+        let (name, source) = self.image_source(options)?;
+        if let Some(environment) = self.execution.environment(&name) {
+            return Ok(environment);
         }
 
-        if self.container.is_none() {
-            None
-        } else {
-            let container = self.container.clone().unwrap();
-            let block = self.blocks.pop();
-
-            match block {
-                Some(block) => {
-                    let result = match block.options.cmd {
-                        Some(ref cmd) => {
-                            info!("executing command: {}", cmd);
-                            container.exec(cmd, &block.code)
+        let starting = self
+            .starting
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_default()
+            .clone();
+        let mut failed = starting.lock().unwrap();
+        // another block may have started it while this one waited
+        if let Some(environment) = self.execution.environment(&name) {
+            return Ok(environment);
+        }
+        if let Some(ref error) = *failed {
+            return Err(io::Error::other(error.clone()));
+        }
+
+        let fingerprint = self.fingerprint(&source);
+        if let Some(environment) = self.manager.get(&self.notebook_path, &name, &fingerprint) {
+            debug!("reusing environment {}", name);
+            self.execution
+                .environments
+                .lock()
+                .unwrap()
+                .insert(name, environment.clone());
+            return Ok(environment);
+        }
+
+        info!("starting environment {}", name);
+        let executor = self.create_executor(source);
+        if let Err(err) = self.start_environment(&name, &*executor, on_build_log) {
+            let error = format!("unable to start environment {}: {}", name, err);
+            *failed = Some(error.clone());
+            return Err(io::Error::new(err.kind(), error));
+        }
+
+        let environment = Arc::new(Environment {
+            executor,
+            sessions: Sessions::default(),
+        });
+        self.manager
+            .insert(&self.notebook_path, &name, fingerprint, environment.clone());
+        self.execution
+            .environments
+            .lock()
+            .unwrap()
+            .insert(name, environment.clone());
+        Ok(environment)
+    }
+
+    /// Identifies everything an environment is started from, so running
+    /// environments are replaced when any of it changes.
+    fn fingerprint(&self, source: &docker::ImageSource) -> String {
+        let image = match *source {
+            // an unreadable Dockerfile fails when the image is built instead
+            docker::ImageSource::Build {
+                ref docker_file,
+                ref name,
+                ..
+            } => format!("{}:{}", name, docker::source_hash(docker_file).unwrap_or_default()),
+            docker::ImageSource::Existing(ref name) => name.clone(),
+        };
+
+        match self.backend {
+            Backend::Docker => format!(
+                "{} {:?} {:?} {}",
+                image,
+                self.options.network.as_ref().unwrap_or(&self.default_network),
+                self.options.limits,
+                self.container_user()
+            ),
+            Backend::Local => String::from("local"),
+        }
+    }
+
+    fn start_environment<G>(&self, name: &str, executor: &dyn Executor, mut on_build_log: G) -> io::Result<()>
+    where
+        G: FnMut(&str, String),
+    {
+        executor.build(&mut |data| on_build_log(name, data))?;
+        executor.start()
+    }
+
+    /// Runs every block, streaming their output to `on_output` and the
+    /// output of any image they need built to `on_build_log`, and passing
+    /// each block's result to `on_result` once it finishes. Blocks' output
+    /// is converted to HTML, with any colors kept unless they say otherwise. Blocks that don't
+    /// have to wait on each other are run at the same time, up to the
+    /// notebook's concurrency. `on_waiting` is called when an interactive
+    /// block looks like it is waiting for input.
+    pub fn execute<F, G, H, W>(&mut self, on_output: F, on_build_log: G, on_result: H, on_waiting: W)
+    where
+        F: Fn(&str, Stream, HtmlChunk) + Sync,
+        G: Fn(&str, String) + Sync,
+        H: Fn(String, io::Result<ExecOutput>) + Sync,
+        W: Fn(&str) + Sync,
+    {
+        let mut pending = mem::take(&mut self.blocks);
+        if self.cache.is_some() {
+            self.cache_keys(&mut pending);
+        }
+        let schedule = Mutex::new(Schedule {
+            pending,
+            ..Schedule::default()
+        });
+        let finished = Condvar::new();
+
+        let concurrency = self
+            .options
+            .concurrency
+            .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+            .unwrap_or(1);
+        let workers = concurrency.clamp(1, schedule.lock().unwrap().pending.len().max(1));
+        info!("running notebook with {} workers", workers);
+
+        let renderer = &*self;
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let (block, failed) = {
+                        let mut schedule = schedule.lock().unwrap();
+                        loop {
+                            if schedule.pending.is_empty() {
+                                return;
+                            }
+                            if let Some(next) = schedule.next() {
+                                break next;
+                            }
+                            schedule = finished.wait(schedule).unwrap();
                         }
-                        None => return None,
                     };
 
-                    Some((block.id, result.unwrap()))
+                    let result = match failed {
+                        Some(failed) => {
+                            info!("skipping block {}, {} failed", block.id, failed);
+                            let error = format!("skipped because {} failed", failed);
+                            Some(Err(io::Error::other(error)))
+                        }
+                        None => renderer.execute_block(&block, &on_output, &on_build_log, &on_waiting),
+                    };
+
+                    // blocks with nothing to run count as having succeeded
+                    let succeeded = match result {
+                        Some(Ok(ref output)) => output.succeeded(),
+                        Some(Err(_)) => false,
+                        None => true,
+                    };
+                    if let Some(result) = result {
+                        let colors = block.options.colors.unwrap_or(true);
+                        let result = result.map(|output| ExecOutput {
+                            stdout: ansi::to_html(&output.stdout, colors),
+                            stderr: ansi::to_html(&output.stderr, colors),
+                            ..output
+                        });
+                        on_result(block.id.clone(), result);
+                    }
+
+                    schedule.lock().unwrap().finish(block.id, succeeded);
+                    finished.notify_all();
+                });
+            }
+        });
+    }
+
+    /// Runs a block, returning `None` if it has nothing to run.
+    fn execute_block(
+        &self,
+        block: &CodeBlock,
+        on_output: &(dyn Fn(&str, Stream, HtmlChunk) + Sync),
+        on_build_log: &(dyn Fn(&str, String) + Sync),
+        on_waiting: &(dyn Fn(&str) + Sync),
+    ) -> Option<io::Result<ExecOutput>> {
+        if self.execution.is_cancelled(&block.id) {
+            info!("skipping cancelled block {}", block.id);
+            return Some(Ok(ExecOutput {
+                stdout: String::new(),
+                stderr: String::new(),
+                binary: None,
+                status: ExecStatus::Cancelled,
+                exit_code: None,
+                signal: None,
+                duration: Duration::from_secs(0),
+                cached: false,
+                artifacts: Vec::new(),
+            }));
+        }
+
+        if let Some(ref error) = block.error {
+            return Some(Err(io::Error::new(io::ErrorKind::InvalidInput, error.clone())));
+        }
+
+        if block.options.session.is_none() && block.options.cmd.is_none() {
+            debug!("block {:?} doesnt have a command", block);
+            return None;
+        }
+
+        let interactive = block.options.interactive.unwrap_or(false);
+        if interactive && block.options.session.is_some() {
+            let error = "blocks in a session can't be interactive";
+            return Some(Err(io::Error::new(io::ErrorKind::InvalidInput, error)));
+        }
+
+        if let Err(err) = artifacts::validate(&self.notebook_dir, &block.options.artifacts) {
+            return Some(Err(err));
+        }
+
+        let cache = self.cache.as_ref().and_then(|cache| {
+            block.cache_key.as_ref().map(|key| (cache, key))
+        });
+        if let Some((cache, key)) = cache {
+            // rebuilds run everything again
+            if !self.rebuild {
+                if let Some(output) = cache.get(key) {
+                    info!("using cached output of block {}", block.id);
+                    return Some(Ok(self.with_artifacts(block, output)));
                 }
-                None => {
-                    debug!("block {:?} doesnt have a command", block);
+            }
+        }
+
+        let environment = match self.environment(&block.options, on_build_log) {
+            Ok(environment) => environment,
+            Err(err) => {
+                error!("error starting notebook: {}", err);
+                return Some(Err(err));
+            }
+        };
+        let executor = &environment.executor;
+
+        let execution = self.execution.clone();
+        let timeout = block
+            .options
+            .timeout
+            .or(self.options.timeout)
+            .map(Duration::from_secs);
+        let mut env: Vec<_> = self
+            .options
+            .env
+            .iter()
+            .filter(|&(name, _)| !block.options.env.contains_key(name))
+            .chain(block.options.env.iter())
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        env.sort();
+
+        let colors = block.options.colors.unwrap_or(true);
+        let mut converters = [ansi::Converter::new(colors), ansi::Converter::new(colors)];
+        let mut on_output = |stream, data: String| {
+            let converter = match stream {
+                Stream::Stdout => &mut converters[0],
+                Stream::Stderr => &mut converters[1],
+            };
+            on_output(&block.id, stream, converter.push(&data))
+        };
+
+        let result = match (block.options.session.as_ref(), block.options.cmd.as_ref()) {
+            (Some(language), _) => {
+                info!("executing block in {} session", language);
+                let request = ExecRequest {
+                    id: &block.id,
+                    cmd: "",
+                    code: &block.code,
+                    env: &env,
+                    timeout,
+                    input: None,
+                };
+                environment.sessions.exec(
+                    &**executor,
+                    language,
+                    &request,
+                    &|| execution.is_cancelled(&block.id),
+                    &mut on_output,
+                )
+            }
+            (None, Some(cmd)) => {
+                info!("executing command: {}", cmd);
+                let (sender, receiver) = channel();
+                let on_waiting = || on_waiting(&block.id);
+                let input = if interactive {
+                    execution.inputs.lock().unwrap().insert(block.id.clone(), sender);
+                    Some(Input {
+                        data: &receiver,
+                        on_waiting: &on_waiting,
+                    })
+                } else {
                     None
+                };
+                let request = ExecRequest {
+                    id: &block.id,
+                    cmd,
+                    code: &block.code,
+                    env: &env,
+                    timeout,
+                    input,
+                };
+                let result = executor.exec(
+                    &request,
+                    &|| execution.is_cancelled(&block.id),
+                    &mut on_output,
+                );
+                execution.inputs.lock().unwrap().remove(&block.id);
+                result
+            }
+            (None, None) => return None,
+        };
+
+        if let (Some((cache, key)), Ok(output)) = (cache, result.as_ref()) {
+            if output.succeeded() {
+                if let Err(err) = cache.insert(key, output) {
+                    warn!("unable to cache output of block {}: {}", block.id, err);
                 }
             }
         }
+
+        Some(result.map(|output| self.with_artifacts(block, output)))
+    }
+
+    /// Adds the files matching the block's `artifacts` patterns to its output.
+    fn with_artifacts(&self, block: &CodeBlock, mut output: ExecOutput) -> ExecOutput {
+        match artifacts::collect(&self.notebook_dir, &block.options.artifacts) {
+            Ok(artifacts) => output.artifacts = artifacts,
+            Err(err) => warn!("unable to collect artifacts of block {}: {}", block.id, err),
+        }
+        output
+    }
+
+    /// Works out the keys blocks' outputs are cached under, from the block,
+    /// the notebook options, the environment it runs in and the keys of the
+    /// blocks it runs after. Blocks in a session aren't cached, as later
+    /// blocks need them to run, and neither is anything after a block that
+    /// isn't cached.
+    fn cache_keys(&self, blocks: &mut VecDeque<CodeBlock>) {
+        let notebook_options = serde_json::to_value(&self.options)
+            .map(|options| options.to_string())
+            .unwrap_or_default();
+        let mut keys: HashMap<String, Option<String>> = HashMap::new();
+        let mut fingerprints: HashMap<String, String> = HashMap::new();
+
+        for block in blocks.iter_mut() {
+            let cacheable = block.options.cache != Some(false)
+                && block.options.session.is_none()
+                && block.options.interactive != Some(true)
+                && block.error.is_none();
+            let upstream: Option<Vec<&String>> = block
+                .after
+                .iter()
+                .map(|id| keys.get(id).and_then(|key| key.as_ref()))
+                .collect();
+            let environment = self.image_source(&block.options).ok().map(|(name, source)| {
+                fingerprints
+                    .entry(name)
+                    .or_insert_with(|| self.fingerprint(&source))
+                    .clone()
+            });
+
+            let key = match (cacheable, upstream, environment) {
+                (true, Some(upstream), Some(environment)) => {
+                    let options = serde_json::to_value(&block.options)
+                        .map(|options| options.to_string())
+                        .unwrap_or_default();
+
+                    let notebook_path = self.notebook_path.to_string_lossy();
+                    let parts = [&*notebook_path, &notebook_options, &environment, &options, &block.code];
+
+                    let mut hasher = Sha256::new();
+                    for part in parts.iter().cloned().chain(upstream.iter().map(|key| key.as_str())) {
+                        hasher.update(part.as_bytes());
+                        hasher.update([0]);
+                    }
+                    Some(
+                        hasher
+                            .finalize()
+                            .iter()
+                            .map(|byte| format!("{:02x}", byte))
+                            .collect(),
+                    )
+                }
+                _ => None,
+            };
+
+            keys.insert(block.id.clone(), key.clone());
+            block.cache_key = key;
+        }
+    }
+}
+
+/// Blocks waiting to run and the outcome of those that have, shared by the
+/// threads running a notebook.
+#[derive(Default)]
+struct Schedule {
+    pending: VecDeque<CodeBlock>,
+    running: usize,
+    finished: HashSet<String>,
+    /// Blocks that failed or were cancelled, by id
+    failed: HashSet<String>,
+}
+
+impl Schedule {
+    /// Takes the first block that is ready to run, along with the id of a
+    /// dependency that failed if there is one.
+    fn next(&mut self) -> Option<(CodeBlock, Option<String>)> {
+        let finished = &self.finished;
+        let ready = self.pending.iter().position(|block| {
+            block.error.is_some() || block.after.iter().all(|id| finished.contains(id))
+        });
+        // nothing would finish to make a block ready, so run one anyway
+        // rather than waiting forever
+        let ready = ready.or(if self.running == 0 { Some(0) } else { None })?;
+
+        let block = self.pending.remove(ready)?;
+        let failed = block
+            .dependencies
+            .iter()
+            .find(|id| self.failed.contains(*id))
+            .cloned();
+        self.running += 1;
+        Some((block, failed))
+    }
+
+    fn finish(&mut self, id: String, succeeded: bool) {
+        self.running -= 1;
+        if !succeeded {
+            self.failed.insert(id.clone());
+        }
+        self.finished.insert(id);
+    }
+}
+
+/// Orders blocks so each one runs after the blocks it depends on, otherwise
+/// keeping them in document order. Blocks that depend on a block that doesn't
+/// exist, or that are stuck behind a dependency cycle, are given an error.
+fn order_blocks(mut blocks: Vec<CodeBlock>) -> VecDeque<CodeBlock> {
+    // blocks can be referred to by id or by name
+    let mut ids: HashMap<String, String> = HashMap::new();
+    for block in &blocks {
+        if let Some(ref name) = block.options.name {
+            ids.insert(name.clone(), block.id.clone());
+        }
+        ids.insert(block.id.clone(), block.id.clone());
+    }
+
+    for block in &mut blocks {
+        for reference in &block.options.depends {
+            match ids.get(reference) {
+                Some(id) => block.dependencies.push(id.clone()),
+                None => block.error = Some(format!("depends on unknown block {}", reference)),
+            }
+        }
+    }
+
+    let mut remaining: Vec<Option<CodeBlock>> = blocks.into_iter().map(Some).collect();
+
+    // repeatedly take the first block whose dependencies have all been taken
+    let mut ordered = VecDeque::with_capacity(remaining.len());
+    let mut done: HashSet<String> = HashSet::new();
+    loop {
+        let next = remaining.iter().position(|block| {
+            block.as_ref().is_some_and(|block| {
+                block.dependencies.iter().all(|id| done.contains(id))
+            })
+        });
+        match next {
+            Some(index) => {
+                let block = remaining[index].take().unwrap();
+                done.insert(block.id.clone());
+                ordered.push_back(block);
+            }
+            None => break,
+        }
+    }
+
+    for mut block in remaining.into_iter().flatten() {
+        warn!("block {} is part of or depends on a dependency cycle", block.id);
+        block.error = Some(String::from("part of or depends on a dependency cycle"));
+        ordered.push_back(block);
+    }
+
+    // blocks without dependencies wait for every block before them, unless
+    // they are parallel, then they only wait for the last one that isn't
+    let mut last: Option<String> = None;
+    let mut since_last: Vec<String> = Vec::new();
+    for block in &mut ordered {
+        if !block.options.depends.is_empty() {
+            block.after = block.dependencies.clone();
+        } else if block.options.parallel.unwrap_or(false) {
+            block.after = last.iter().cloned().collect();
+        } else {
+            block.after = mem::take(&mut since_last);
+            last = Some(block.id.clone());
+        }
+        since_last.push(block.id.clone());
+    }
+
+    ordered
+}
+
+/// Turns `name` into something docker accepts in an image name.
+fn image_name_component(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates the `number`th block of a notebook with options `options`.
+    fn block(number: usize, options: &str) -> CodeBlock {
+        let mut block = CodeBlock::new(number, 0);
+        block.options = serde_json::from_str(options).unwrap();
+        block
+    }
+
+    fn ids(blocks: &VecDeque<CodeBlock>) -> Vec<&str> {
+        blocks.iter().map(|block| block.id.as_str()).collect()
+    }
+
+    fn find<'a>(blocks: &'a VecDeque<CodeBlock>, id: &str) -> &'a CodeBlock {
+        blocks.iter().find(|block| block.id == id).unwrap()
+    }
+
+    #[test]
+    fn runs_dependencies_first() {
+        let blocks = order_blocks(vec![
+            block(0, "{}"),
+            block(1, r#"{"depends": ["block-2"]}"#),
+            block(2, "{}"),
+        ]);
+        assert_eq!(ids(&blocks), vec!["block-0", "block-2", "block-1"]);
+        assert_eq!(find(&blocks, "block-1").after, vec!["block-2"]);
+        assert_eq!(find(&blocks, "block-2").after, vec!["block-0"]);
+    }
+
+    #[test]
+    fn depends_on_names() {
+        let blocks = order_blocks(vec![
+            block(0, r#"{"depends": ["setup"]}"#),
+            block(1, r#"{"name": "setup"}"#),
+        ]);
+        assert_eq!(ids(&blocks), vec!["block-1", "block-0"]);
+        assert_eq!(find(&blocks, "block-0").dependencies, vec!["block-1"]);
+    }
+
+    #[test]
+    fn unknown_dependencies_are_errors() {
+        let blocks = order_blocks(vec![block(0, r#"{"depends": ["missing"]}"#), block(1, "{}")]);
+        assert_eq!(
+            find(&blocks, "block-0").error.as_deref(),
+            Some("depends on unknown block missing")
+        );
+        assert_eq!(find(&blocks, "block-1").error, None);
+    }
+
+    #[test]
+    fn cycles_are_errors() {
+        let blocks = order_blocks(vec![
+            block(0, r#"{"depends": ["block-1"]}"#),
+            block(1, r#"{"depends": ["block-0"]}"#),
+            block(2, r#"{"depends": ["block-1"]}"#),
+            block(3, "{}"),
+        ]);
+        assert_eq!(ids(&blocks), vec!["block-3", "block-0", "block-1", "block-2"]);
+        for id in &["block-0", "block-1", "block-2"] {
+            assert!(find(&blocks, id).error.is_some(), "{} has no error", id);
+        }
+        assert_eq!(find(&blocks, "block-3").error, None);
+    }
+
+    #[test]
+    fn parallel_blocks_only_wait_for_the_last_other_block() {
+        let blocks = order_blocks(vec![
+            block(0, "{}"),
+            block(1, r#"{"parallel": true}"#),
+            block(2, r#"{"parallel": true}"#),
+            block(3, "{}"),
+        ]);
+        let after = |id| find(&blocks, id).after.clone();
+        assert!(after("block-0").is_empty());
+        assert_eq!(after("block-1"), vec!["block-0"]);
+        assert_eq!(after("block-2"), vec!["block-0"]);
+        assert_eq!(after("block-3"), vec!["block-0", "block-1", "block-2"]);
+    }
+
+    #[test]
+    fn schedule_runs_blocks_once_they_are_ready() {
+        let mut schedule = Schedule {
+            pending: order_blocks(vec![
+                block(0, "{}"),
+                block(1, r#"{"parallel": true}"#),
+                block(2, "{}"),
+            ]),
+            ..Schedule::default()
+        };
+
+        let (first, _) = schedule.next().unwrap();
+        assert_eq!(first.id, "block-0");
+        assert!(schedule.next().is_none());
+
+        schedule.finish(first.id, true);
+        let (second, _) = schedule.next().unwrap();
+        assert_eq!(second.id, "block-1");
+        // block-2 waits for the parallel block too
+        assert!(schedule.next().is_none());
+
+        schedule.finish(second.id, true);
+        let (third, failed) = schedule.next().unwrap();
+        assert_eq!(third.id, "block-2");
+        assert_eq!(failed, None);
+        schedule.finish(third.id, true);
+        assert!(schedule.pending.is_empty());
+    }
+
+    #[test]
+    fn schedule_skips_blocks_after_a_failed_dependency() {
+        let mut schedule = Schedule {
+            pending: order_blocks(vec![
+                block(0, "{}"),
+                block(1, r#"{"depends": ["block-0"]}"#),
+                block(2, "{}"),
+            ]),
+            ..Schedule::default()
+        };
+
+        let (first, _) = schedule.next().unwrap();
+        schedule.finish(first.id, false);
+
+        let (second, failed) = schedule.next().unwrap();
+        assert_eq!(second.id, "block-1");
+        assert_eq!(failed.as_deref(), Some("block-0"));
+        schedule.finish(second.id, false);
+
+        // blocks that don't depend on it still run
+        let (third, failed) = schedule.next().unwrap();
+        assert_eq!(third.id, "block-2");
+        assert_eq!(failed, None);
+    }
+
+    #[test]
+    fn schedule_runs_blocks_with_errors_straight_away() {
+        let mut schedule = Schedule {
+            pending: order_blocks(vec![
+                block(0, "{}"),
+                block(1, r#"{"depends": ["block-2"]}"#),
+                block(2, r#"{"depends": ["block-1"]}"#),
+            ]),
+            ..Schedule::default()
+        };
+
+        let (first, _) = schedule.next().unwrap();
+        assert_eq!(first.id, "block-0");
+        // the blocks in the cycle don't wait for anything, they only fail
+        let (second, _) = schedule.next().unwrap();
+        assert_eq!(second.id, "block-1");
+        assert!(second.error.is_some());
     }
 }
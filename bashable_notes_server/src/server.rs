@@ -1,11 +1,34 @@
 use std::thread;
 use std::path::Path;
-use ws::{CloseCode, Error, Handler, Message, Result, Sender};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io::{self, Write};
+use ws::{CloseCode, Error, Handler, Message, Request, Response, Result, Sender};
 use serde_json;
-use renderer::{FileTree, Renderer};
+use std::sync::{Arc, Mutex};
+use artifacts::Artifact;
+use binary::Binary;
+use manager::{Environment, Manager};
+use renderer::{Execution, FileTree, Renderer};
+use executor::{self, ExecStatus, Process, Stream, Terminal};
 
 pub struct Server {
     pub out: Sender,
+    pub execution: Execution,
+    pub manager: Arc<Manager>,
+    /// Terminals the client has open, by id, or `None` while one is opening
+    pub terminals: Arc<Mutex<HashMap<String, Option<OpenTerminal>>>>,
+    /// Origins of the pages allowed to connect
+    pub origins: Arc<Vec<String>>,
+}
+
+/// A terminal opened by the client. Its environment is kept running until
+/// it is closed.
+pub struct OpenTerminal {
+    stdin: Box<dyn Write + Send>,
+    resize: Box<dyn Fn(u16, u16) -> io::Result<()> + Send>,
+    kill: Box<dyn Fn() -> io::Result<()> + Send>,
+    _environment: Arc<Environment>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -13,6 +36,10 @@ pub enum AppMessage {
     OpenFile {
         path: String,
     },
+    /// Opens a file after rebuilding its images from scratch
+    Rebuild {
+        path: String,
+    },
     FileUpdate {
         path: String,
     },
@@ -21,31 +48,320 @@ pub enum AppMessage {
         path: String,
         markdown: String,
     },
+    /// Output of a running block, as HTML
+    OutputChunk {
+        id: String,
+        stream: Stream,
+        /// Lines the chunk finished
+        data: String,
+        /// The line still being written, replacing the last chunk's
+        partial: String,
+    },
+    /// Result of a block, its `stdout` and `stderr` are HTML
     Output {
         id: String,
         stdout: String,
         stderr: String,
+        /// Set if stdout isn't UTF-8 text, e.g. an image
+        binary: Option<Binary>,
+        status: ExecStatus,
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+        /// Seconds the block ran for
+        duration: f64,
+        success: bool,
+        /// Set if the output is from an earlier run of an unchanged block
+        cached: bool,
+        artifacts: Vec<Artifact>,
+    },
+    Cancel {
+        id: String,
+    },
+    /// Input for an interactive block, written to its stdin
+    Stdin {
+        id: String,
+        data: String,
+    },
+    /// An interactive block looks like it is waiting for input
+    AwaitingInput {
+        id: String,
+    },
+    /// Opens a shell in an environment of the notebook at `path`, the
+    /// default one unless `environment` is given
+    TerminalOpen {
+        id: String,
+        path: String,
+        environment: Option<String>,
+        rows: u16,
+        cols: u16,
+    },
+    /// Keys typed into a terminal
+    TerminalInput {
+        id: String,
+        data: String,
+    },
+    TerminalResize {
+        id: String,
+        rows: u16,
+        cols: u16,
+    },
+    /// Output of a terminal, with its escape sequences
+    TerminalOutput {
+        id: String,
+        data: String,
     },
+    TerminalClose {
+        id: String,
+    },
+    /// The terminal's shell has exited
+    TerminalClosed {
+        id: String,
+    },
+    /// Stops a notebook's environment, or all of them if none is given, so
+    /// the next run starts from a fresh container
+    RestartEnvironment {
+        path: String,
+        environment: Option<String>,
+    },
+    Pause,
+    Resume,
+    /// Output of building one of a notebook's images
+    BuildLog {
+        path: String,
+        environment: String,
+        data: String,
+    },
+    Paused,
+    Resumed,
+    /// Something went wrong, in the notebook at `path` or its block `id` if
+    /// given
     Error {
         error: String,
+        path: Option<String>,
+        id: Option<String>,
     },
     FileTree {
         root: Vec<FileTree>,
     },
 }
 
-impl Handler for Server {
-    fn on_message(&mut self, msg: Message) -> Result<()> {
+impl Server {
+    /// Returns a function that sends messages to the client.
+    fn sender(&self) -> impl Fn(AppMessage) + Send + 'static {
         let out = self.out.clone();
 
-        let thread_send = move |app_msg: AppMessage| {
-            let out = out.clone();
-            thread::spawn(move || {
-                let text = serde_json::to_string(&app_msg).unwrap();
-                out.send(Message::Text(text)).unwrap();
-                debug!("message sent");
+        // messages are queued in order so a block's output chunks always
+        // arrive before its final output
+        move |app_msg: AppMessage| {
+            let text = serde_json::to_string(&app_msg).unwrap();
+            out.send(Message::Text(text)).unwrap();
+            debug!("message sent");
+        }
+    }
+
+    /// Renders a notebook, then runs its blocks in the background.
+    fn open_file(&mut self, path: String, rebuild: bool) {
+        let send = self.sender();
+
+        let mut renderer = Renderer::new(self.manager.clone());
+        if rebuild {
+            renderer.force_rebuild();
+        }
+        // the previous run can't be controlled once it is replaced, so it is
+        // stopped rather than left running in the background
+        self.execution.stop();
+        self.execution = renderer.execution();
+        match renderer.render(Path::new(&path)) {
+            Ok(markdown) => send(AppMessage::Markdown {
+                path: path.clone(),
+                markdown,
+            }),
+            Err(err) => {
+                warn!("unable to render {}: {}", path, err);
+                return send(AppMessage::Error {
+                    error: format!("unable to open {}: {}", path, err),
+                    path: Some(path),
+                    id: None,
+                });
+            }
+        }
+
+        // results of a stopped run would overwrite the new run's output
+        let execution = self.execution.clone();
+        let send = move |app_msg: AppMessage| {
+            if !execution.is_stopped() {
+                send(app_msg)
+            }
+        };
+
+        thread::spawn(move || {
+            renderer.execute(
+                |id, stream, chunk| {
+                    send(AppMessage::OutputChunk {
+                        id: id.to_string(),
+                        stream,
+                        data: chunk.lines,
+                        partial: chunk.partial,
+                    })
+                },
+                |environment, data| {
+                    send(AppMessage::BuildLog {
+                        path: path.clone(),
+                        environment: environment.to_string(),
+                        data,
+                    })
+                },
+                |id, result| match result {
+                    Ok(output) => send(AppMessage::Output {
+                        id,
+                        success: output.succeeded(),
+                        stdout: output.stdout,
+                        stderr: output.stderr,
+                        binary: output.binary,
+                        status: output.status,
+                        exit_code: output.exit_code,
+                        signal: output.signal,
+                        duration: output.duration.as_secs_f64(),
+                        cached: output.cached,
+                        artifacts: output.artifacts,
+                    }),
+                    Err(err) => send(AppMessage::Error {
+                        error: err.to_string(),
+                        path: Some(path.clone()),
+                        id: Some(id),
+                    }),
+                },
+                |id| send(AppMessage::AwaitingInput { id: id.to_string() }),
+            );
+            renderer.clean_up();
+        });
+    }
+
+    /// Opens a terminal in the background, as its environment may need to be
+    /// built first, then streams its output until the shell exits.
+    fn open_terminal(&mut self, id: String, path: String, environment: Option<String>, size: (u16, u16)) {
+        let send = self.sender();
+        // the id is taken before the terminal opens, so opening it twice fails
+        match self.terminals.lock().unwrap().entry(id.clone()) {
+            Entry::Occupied(_) => {
+                return send(AppMessage::Error {
+                    error: format!("terminal {} is already open", id),
+                    path: Some(path),
+                    id: Some(id),
+                })
+            }
+            Entry::Vacant(entry) => entry.insert(None),
+        };
+
+        let manager = self.manager.clone();
+        let terminals = self.terminals.clone();
+        thread::spawn(move || {
+            let mut renderer = Renderer::new(manager);
+            let opened = renderer.open_terminal(Path::new(&path), environment.as_deref(), size, &|environment, data| {
+                send(AppMessage::BuildLog {
+                    path: path.clone(),
+                    environment: environment.to_string(),
+                    data,
+                })
             });
+            let (environment, terminal) = match opened {
+                Ok(opened) => opened,
+                Err(err) => {
+                    warn!("unable to open terminal {}: {}", id, err);
+                    terminals.lock().unwrap().remove(&id);
+                    return send(AppMessage::Error {
+                        error: format!("unable to open terminal: {}", err),
+                        path: Some(path),
+                        id: Some(id),
+                    });
+                }
+            };
+            info!("opened terminal {}", id);
+
+            let Terminal {
+                process: Process { stdin, output, kill },
+                resize,
+            } = terminal;
+            let opened = OpenTerminal {
+                stdin,
+                resize,
+                kill,
+                _environment: environment,
+            };
+            match terminals.lock().unwrap().get_mut(&id) {
+                Some(slot) => *slot = Some(opened),
+                None => {
+                    // the client disconnected while it was opening
+                    if let Err(err) = (opened.kill)() {
+                        warn!("failed to kill terminal {}: {}", id, err);
+                    }
+                    return;
+                }
+            }
+
+            // bytes of a character split between reads
+            let mut pending = Vec::new();
+            for (_, data) in output.iter() {
+                pending.extend(data);
+                let data = executor::decode_utf8(&mut pending);
+                if !data.is_empty() {
+                    send(AppMessage::TerminalOutput { id: id.clone(), data });
+                }
+            }
+
+            info!("terminal {} closed", id);
+            terminals.lock().unwrap().remove(&id);
+            send(AppMessage::TerminalClosed { id });
+        });
+    }
+
+    /// Runs `f` on the open terminal `id`, telling the client if it fails.
+    fn with_terminal<F>(&self, id: String, f: F)
+    where
+        F: FnOnce(&mut OpenTerminal) -> io::Result<()>,
+    {
+        let result = match self.terminals.lock().unwrap().get_mut(&id) {
+            Some(Some(terminal)) => f(terminal),
+            Some(None) => Err(io::Error::new(io::ErrorKind::WouldBlock, "terminal is still opening")),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "terminal isn't open")),
         };
+        if let Err(err) = result {
+            self.sender()(AppMessage::Error {
+                error: format!("terminal {}: {}", id, err),
+                path: None,
+                id: Some(id),
+            });
+        }
+    }
+
+    /// Kills every terminal the client has open, and those still opening
+    /// once they are.
+    fn close_terminals(&self) {
+        for (id, terminal) in self.terminals.lock().unwrap().drain() {
+            let Some(terminal) = terminal else { continue };
+            if let Err(err) = (terminal.kill)() {
+                warn!("failed to kill terminal {}: {}", id, err);
+            }
+        }
+    }
+}
+
+impl Handler for Server {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        // browsers always send an origin, so this keeps other websites open
+        // in the same browser from running code and opening terminals.
+        // Clients that aren't browsers don't send one.
+        if let Some(origin) = req.origin()? {
+            if !self.origins.iter().any(|allowed| allowed == origin) {
+                warn!("rejecting connection from origin {}", origin);
+                return Ok(Response::new(403, "Forbidden", b"origin not allowed".to_vec()));
+            }
+        }
+        Response::from_request(req)
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let send = self.sender();
 
         debug!("message from client: {}", msg);
 
@@ -59,29 +375,61 @@ impl Handler for Server {
 
         match serde_json::from_str(&msg_text) {
             Ok(msg) => match msg {
-                AppMessage::OpenFile { path } => {
-                    let mut renderer = Renderer::new();
-                    thread_send(AppMessage::Markdown {
-                        path: path.clone(),
-                        markdown: renderer.render(Path::new(&path)),
-                    });
-
-                    thread::spawn(move || {
-                        while !renderer.execution_finished() {
-                            let exec_result = renderer.execute();
-                            if let Some((id, (stdout, stderr))) = exec_result {
-                                thread_send(AppMessage::Output { id, stdout, stderr });
-                            }
-                        }
-                        renderer.clean_up();
-                    });
-                }
+                AppMessage::OpenFile { path } => self.open_file(path, false),
+                AppMessage::Rebuild { path } => self.open_file(path, true),
                 AppMessage::GetTree => {
-                    let renderer = Renderer::new();
-                    thread_send(AppMessage::FileTree {
+                    let renderer = Renderer::new(self.manager.clone());
+                    send(AppMessage::FileTree {
                         root: renderer.render_file_tree(),
                     })
                 }
+                AppMessage::Cancel { id } => self.execution.cancel(&id),
+                AppMessage::Stdin { id, data } => {
+                    if let Err(err) = self.execution.send_input(&id, data) {
+                        send(AppMessage::Error {
+                            error: format!("unable to send input: {}", err),
+                            path: None,
+                            id: Some(id),
+                        })
+                    }
+                }
+                AppMessage::TerminalOpen {
+                    id,
+                    path,
+                    environment,
+                    rows,
+                    cols,
+                } => self.open_terminal(id, path, environment, (rows, cols)),
+                AppMessage::TerminalInput { id, data } => self.with_terminal(id, |terminal| {
+                    terminal
+                        .stdin
+                        .write_all(data.as_bytes())
+                        .and_then(|_| terminal.stdin.flush())
+                }),
+                AppMessage::TerminalResize { id, rows, cols } => {
+                    self.with_terminal(id, |terminal| (terminal.resize)(rows, cols))
+                }
+                AppMessage::TerminalClose { id } => self.with_terminal(id, |terminal| (terminal.kill)()),
+                AppMessage::RestartEnvironment { path, environment } => {
+                    let renderer = Renderer::new(self.manager.clone());
+                    renderer.restart_environment(Path::new(&path), environment.as_deref());
+                }
+                AppMessage::Pause => send(match self.execution.pause() {
+                    Ok(()) => AppMessage::Paused,
+                    Err(err) => AppMessage::Error {
+                        error: format!("unable to pause notebook: {}", err),
+                        path: None,
+                        id: None,
+                    },
+                }),
+                AppMessage::Resume => send(match self.execution.resume() {
+                    Ok(()) => AppMessage::Resumed,
+                    Err(err) => AppMessage::Error {
+                        error: format!("unable to resume notebook: {}", err),
+                        path: None,
+                        id: None,
+                    },
+                }),
                 _ => warn!("unexpected message"),
             },
             Err(err) => warn!("unable to parse message: {}", err),
@@ -92,6 +440,10 @@ impl Handler for Server {
 
     fn on_close(&mut self, code: CloseCode, reason: &str) {
         println!("WebSocket closing for ({:?}) {}", code, reason);
+        // nobody is left to see the output, and a running block keeps its
+        // environment from being stopped once idle
+        self.execution.stop();
+        self.close_terminals();
     }
 
     fn on_error(&mut self, err: Error) {
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{SystemTime, UNIX_EPOCH};
+use executor::{self, ExecOutput, ExecRequest, ExecStatus, Executor, Process, Stream};
+
+// Each driver reads blocks from stdin as a `TOKEN LINES` header followed by
+// that many lines of code, runs them, then writes "\nTOKEN\n" to both stdout
+// and stderr so the end of the block's output can be found.
+
+const PYTHON_DRIVER: &str = r#"
+import os, sys, traceback
+protocol = os.fdopen(os.dup(0), 'r')
+os.dup2(os.open(os.devnull, os.O_RDONLY), 0)
+scope = {'__name__': '__main__'}
+while True:
+    header = protocol.readline()
+    if not header:
+        break
+    token, lines = header.split()
+    code = ''.join(protocol.readline() for _ in range(int(lines)))
+    try:
+        exec(compile(code, '<block>', 'exec'), scope)
+    except SystemExit:
+        raise
+    except BaseException:
+        traceback.print_exc()
+    for stream in (sys.stdout, sys.stderr):
+        stream.write('\n' + token + '\n')
+        stream.flush()
+"#;
+
+const NODE_DRIVER: &str = r#"
+const fs = require('fs');
+const vm = require('vm');
+global.require = require;
+let pending = Buffer.alloc(0);
+function readLine() {
+  for (;;) {
+    const end = pending.indexOf(10);
+    if (end >= 0) {
+      const line = pending.slice(0, end + 1).toString();
+      pending = pending.slice(end + 1);
+      return line;
+    }
+    const chunk = Buffer.alloc(65536);
+    let n;
+    try {
+      n = fs.readSync(0, chunk, 0, chunk.length, null);
+    } catch (err) {
+      if (err.code === 'EAGAIN') continue;
+      throw err;
+    }
+    if (n === 0) return null;
+    pending = Buffer.concat([pending, chunk.slice(0, n)]);
+  }
+}
+for (;;) {
+  const header = readLine();
+  if (header === null) break;
+  const [token, lines] = header.trim().split(' ');
+  let code = '';
+  for (let i = 0; i < Number(lines); i++) code += readLine();
+  try {
+    vm.runInThisContext(code, { filename: 'block' });
+  } catch (err) {
+    console.error(err && err.stack ? err.stack : err);
+  }
+  process.stdout.write('\n' + token + '\n');
+  process.stderr.write('\n' + token + '\n');
+}
+"#;
+
+const BASH_DRIVER: &str = r#"
+while read -r token lines; do
+  code=""
+  for ((i = 0; i < lines; i++)); do
+    IFS= read -r line
+    code+="$line"$'\n'
+  done
+  eval "$code" </dev/null
+  printf '\n%s\n' "$token"
+  printf '\n%s\n' "$token" >&2
+done
+"#;
+
+const R_DRIVER: &str = r#"
+protocol <- file("stdin", open = "r")
+repeat {
+  header <- readLines(protocol, n = 1)
+  if (length(header) == 0) break
+  parts <- strsplit(header, " ")[[1]]
+  code <- readLines(protocol, n = as.integer(parts[2]))
+  tryCatch({
+    for (expr in parse(text = code)) {
+      result <- withVisible(eval(expr, envir = globalenv()))
+      if (result$visible) print(result$value)
+    }
+  }, error = function(e) message("Error: ", conditionMessage(e)))
+  cat("\n", parts[1], "\n", sep = "")
+  flush(stdout())
+  message("\n", parts[1])
+}
+"#;
+
+/// Returns the command starting a language's interpreter with its driver,
+/// which is passed in the BASHABLE_NOTES_DRIVER enviroment variable.
+fn interpreter(language: &str) -> Option<(&'static str, &'static str)> {
+    match language {
+        "python" => Some((
+            "exec \"$(command -v python3 || command -v python)\" -u -c \"$BASHABLE_NOTES_DRIVER\"",
+            PYTHON_DRIVER,
+        )),
+        "node" => Some(("exec node -e \"$BASHABLE_NOTES_DRIVER\"", NODE_DRIVER)),
+        "bash" => Some(("exec bash -c \"$BASHABLE_NOTES_DRIVER\"", BASH_DRIVER)),
+        "r" | "R" => Some(("exec Rscript -e \"$BASHABLE_NOTES_DRIVER\"", R_DRIVER)),
+        _ => None,
+    }
+}
+
+/// A long running interpreter that blocks are sent to one at a time.
+struct Session {
+    process: Process,
+    /// Enviroment variables the interpreter was started with
+    env: Vec<(String, String)>,
+    /// Set once the interpreter has exited
+    exited: bool,
+}
+
+impl Session {
+    fn start(executor: &dyn Executor, language: &str, env: &[(String, String)]) -> io::Result<Self> {
+        let (cmd, driver) = interpreter(language).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported session language {}", language),
+            )
+        })?;
+
+        info!("starting {} session", language);
+        let id = format!("session-{}", language);
+        let mut vars: Vec<_> = env
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        vars.push(("BASHABLE_NOTES_DRIVER", driver));
+        let process = executor.spawn(&id, cmd, &vars)?;
+
+        Ok(Session {
+            process,
+            env: env.to_vec(),
+            exited: false,
+        })
+    }
+
+    fn run(
+        &mut self,
+        executor: &dyn Executor,
+        request: &ExecRequest,
+        cancelled: &dyn Fn() -> bool,
+        on_output: &mut dyn FnMut(Stream, String),
+    ) -> io::Result<ExecOutput> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.subsec_nanos())
+            .unwrap_or(0);
+        let token = format!("__bashable_notes_{}_{}__", request.id, nanos);
+
+        let mut code = request.code.to_string();
+        if !code.is_empty() && !code.ends_with('\n') {
+            code.push('\n');
+        }
+        write!(self.process.stdin, "{} {}\n{}", token, code.lines().count(), code)?;
+        self.process.stdin.flush()?;
+
+        let marker = format!("\n{}\n", token).into_bytes();
+        let mut pending = [Vec::new(), Vec::new()];
+        let mut finished = [false, false];
+        let output = &self.process.output;
+        let exited = &mut self.exited;
+
+        let mut next = |timeout| {
+            if finished[0] && finished[1] {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            let (stream, data) = match output.recv_timeout(timeout) {
+                Ok(output) => output,
+                Err(RecvTimeoutError::Disconnected) => {
+                    *exited = true;
+                    // pass on whatever was held back looking for the marker
+                    return match pending.iter().position(|data| !data.is_empty()) {
+                        Some(0) => Ok((Stream::Stdout, mem::take(&mut pending[0]))),
+                        Some(_) => Ok((Stream::Stderr, mem::take(&mut pending[1]))),
+                        None => Err(RecvTimeoutError::Disconnected),
+                    };
+                }
+                Err(err) => return Err(err),
+            };
+
+            let i = match stream {
+                Stream::Stdout => 0,
+                Stream::Stderr => 1,
+            };
+            if finished[i] {
+                return Ok((stream, Vec::new()));
+            }
+
+            let pending = &mut pending[i];
+            pending.extend(data);
+            if let Some(end) = pending.windows(marker.len()).position(|window| window == &marker[..]) {
+                finished[i] = true;
+                pending.truncate(end);
+                return Ok((stream, mem::take(pending)));
+            }
+
+            // hold back anything that could be the start of the marker
+            let held = (1..marker.len())
+                .rev()
+                .find(|&len| pending.ends_with(&marker[..len]))
+                .unwrap_or(0);
+            let rest = pending.split_off(pending.len() - held);
+            Ok((stream, mem::replace(pending, rest)))
+        };
+
+        let kill = &self.process.kill;
+        Ok(executor::run_streaming(
+            &mut next,
+            request,
+            cancelled,
+            on_output,
+            &|| kill(),
+            &|| executor.is_paused(),
+            None,
+        ))
+    }
+}
+
+/// The sessions of a running notebook, one per language, which blocks with
+/// the `session` option are run in so they can share state.
+#[derive(Default)]
+pub struct Sessions {
+    /// Each language's session, locked while a block runs in it so blocks
+    /// sharing a session run one at a time
+    sessions: Mutex<HashMap<String, Arc<Mutex<Option<Session>>>>>,
+}
+
+impl Sessions {
+    /// Runs a block in the `language` session, starting it if needed. An
+    /// interpreter's enviroment can't be changed once it is running, so a
+    /// block with other enviroment variables than the session was started
+    /// with gets a fresh session.
+    ///
+    /// A session that is killed by a cancel or timeout, or that exits, is
+    /// thrown away and the next block starts a fresh one.
+    pub fn exec(
+        &self,
+        executor: &dyn Executor,
+        language: &str,
+        request: &ExecRequest,
+        cancelled: &dyn Fn() -> bool,
+        on_output: &mut dyn FnMut(Stream, String),
+    ) -> io::Result<ExecOutput> {
+        let slot = self
+            .sessions
+            .lock()
+            .unwrap()
+            .entry(language.to_string())
+            .or_default()
+            .clone();
+        let mut slot = slot.lock().unwrap();
+
+        let mut session = match slot.take() {
+            Some(session) if session.env == request.env => session,
+            Some(session) => {
+                info!("enviroment of block {} differs, restarting {} session", request.id, language);
+                if let Err(err) = (session.process.kill)() {
+                    warn!("failed to kill {} session: {}", language, err);
+                }
+                Session::start(executor, language, request.env)?
+            }
+            None => Session::start(executor, language, request.env)?,
+        };
+
+        let output = session.run(executor, request, cancelled, on_output)?;
+        if output.status == ExecStatus::Completed && !session.exited {
+            *slot = Some(session);
+        } else {
+            info!("{} session ended", language);
+        }
+
+        Ok(output)
+    }
+
+    /// Closes every session, ending their interpreters.
+    pub fn clear(&self) {
+        self.sessions.lock().unwrap().clear();
+    }
+}